@@ -0,0 +1,327 @@
+use std::fmt;
+
+//
+// Envelope addresses as given in MAIL FROM / RCPT TO, see RFC 5321
+// section 4.1.2. Addresses are stored decoded, i.e. a quoted local part
+// is kept without quotes and backslash escapes, as suggested in
+// https://cr.yp.to/smtp/address.html; the domain is kept lowercase.
+pub struct Address {
+  pub local_part: String,
+  pub domain: String,
+}
+
+// A reverse-path or forward-path with any trailing ESMTP parameters;
+// address is None for the null sender "<>".
+pub struct Path {
+  pub address: Option<Address>,
+  pub parameters: Vec<(String, Option<String>)>,
+}
+
+impl Address {
+
+  // Parse a bare "local@domain" address, e.g. from the configuration file
+  pub fn parse(s: &str) -> Result<Address, String> {
+    let chars: Vec<char> = s.trim().chars().collect();
+    let (address, pos) = try!(parse_mailbox(&chars, 0, false));
+    if pos != chars.len() {
+      return Err(format!("trailing characters in address '{}'", s));
+    }
+    Ok(address)
+  }
+
+  // Address in a form suitable for comparison; local part and domain
+  // are both compared without regard to case.
+  pub fn normalized(&self) -> String {
+    if self.domain.len() == 0 {
+      return self.local_part.to_lowercase();
+    }
+    format!("{}@{}", self.local_part.to_lowercase(), self.domain)
+  }
+}
+
+impl Clone for Address {
+  fn clone (&self) -> Address {
+    Address { local_part: self.local_part.clone(), domain: self.domain.clone() }
+  }
+}
+
+impl fmt::Display for Address {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let dot_string = self.local_part.len() > 0
+      && !self.local_part.starts_with('.') && !self.local_part.ends_with('.')
+      && !self.local_part.contains("..")
+      && self.local_part.chars().all(|c| c == '.' || is_atext(c));
+    if dot_string {
+      try!(write!(f, "{}", self.local_part));
+    } else {
+      try!(write!(f, "\""));
+      for c in self.local_part.chars() {
+        if c == '"' || c == '\\' {
+          try!(write!(f, "\\"));
+        }
+        try!(write!(f, "{}", c));
+      }
+      try!(write!(f, "\""));
+    }
+    if self.domain.len() > 0 {
+      try!(write!(f, "@{}", self.domain));
+    }
+    Ok(())
+  }
+}
+
+impl Path {
+  // Look up an ESMTP parameter by keyword, e.g. "SIZE"
+  pub fn parameter(&self, keyword: &str) -> Option<&Option<String>> {
+    self.parameters.iter().find(|p| p.0 == keyword).map(|p| &p.1)
+  }
+}
+
+// Parse the argument of "MAIL FROM:", i.e. everything after the colon
+pub fn parse_reverse_path(arg: &str) -> Result<Path, String> {
+  parse_path(arg, true)
+}
+
+// Parse the argument of "RCPT TO:", i.e. everything after the colon
+pub fn parse_forward_path(arg: &str) -> Result<Path, String> {
+  parse_path(arg, false)
+}
+
+fn parse_path(arg: &str, reverse: bool) -> Result<Path, String> {
+  // quite a few clients put a space between the colon and the path
+  let chars: Vec<char> = arg.trim().chars().collect();
+  if chars.len() == 0 {
+    return Err("missing path".to_string());
+  }
+
+  let (address, mut pos) = if chars[0] == '<' {
+    let mut pos = 1;
+    if chars.len() > 1 && chars[1] == '>' {
+      if !reverse {
+        return Err("null path not allowed as recipient".to_string());
+      }
+      (None, 2)
+    } else {
+      // skip a source route, "@a.example,@b.example:", it must be ignored
+      if pos < chars.len() && chars[pos] == '@' {
+        while pos < chars.len() && chars[pos] != ':' {
+          pos = pos + 1;
+        }
+        if pos == chars.len() {
+          return Err("unterminated source route".to_string());
+        }
+        pos = pos + 1;
+      }
+      let (address, end) = try!(parse_mailbox(&chars, pos, !reverse));
+      if end >= chars.len() || chars[end] != '>' {
+        return Err("missing '>' after address".to_string());
+      }
+      (Some(address), end + 1)
+    }
+  } else {
+    // not RFC 5321, but seen in the wild: an address without brackets
+    let (address, end) = try!(parse_mailbox(&chars, 0, !reverse));
+    (Some(address), end)
+  };
+
+  // the rest are space separated ESMTP parameters, SIZE=1000 BODY=8BITMIME
+  let mut parameters = Vec::new();
+  while pos < chars.len() {
+    if chars[pos] != ' ' {
+      return Err("missing space before parameter".to_string());
+    }
+    while pos < chars.len() && chars[pos] == ' ' {
+      pos = pos + 1;
+    }
+    let start = pos;
+    while pos < chars.len() && chars[pos] != ' ' {
+      pos = pos + 1;
+    }
+    if start == pos {
+      break;
+    }
+    let param: String = chars[start..pos].iter().cloned().collect();
+    let (keyword, value) = match param.find('=') {
+      Some(i) => (param[..i].to_string(), Some(param[i + 1..].to_string())),
+      None => (param.clone(), None),
+    };
+    if keyword.len() == 0 || !keyword.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+      return Err(format!("invalid parameter '{}'", param));
+    }
+    parameters.push((keyword.to_uppercase(), value));
+  }
+
+  Ok(Path { address: address, parameters: parameters })
+}
+
+// Parse Local-part "@" Domain starting at pos, returns the address and
+// the position of the first character following it. A bare local part
+// is accepted when allow_bare is set, for "RCPT TO:<postmaster>".
+fn parse_mailbox(chars: &[char], mut pos: usize, allow_bare: bool) -> Result<(Address, usize), String> {
+  let mut local_part = String::new();
+
+  if pos < chars.len() && chars[pos] == '"' {
+    // Quoted-string, backslash escapes any printable character
+    pos = pos + 1;
+    loop {
+      if pos >= chars.len() {
+        return Err("unterminated quoted local part".to_string());
+      }
+      match chars[pos] {
+        '"' => { pos = pos + 1; break; },
+        '\\' if pos + 1 < chars.len() => {
+          local_part.push(chars[pos + 1]);
+          pos = pos + 2;
+        },
+        c if c >= ' ' && c != '\\' && c != '\x7f' => {
+          local_part.push(c);
+          pos = pos + 1;
+        },
+        c => return Err(format!("invalid character {:?} in quoted local part", c)),
+      }
+    }
+  } else {
+    // Dot-string
+    while pos < chars.len() && (is_atext(chars[pos]) || chars[pos] == '.') {
+      local_part.push(chars[pos]);
+      pos = pos + 1;
+    }
+    if local_part.len() == 0 || local_part.starts_with('.') || local_part.ends_with('.') || local_part.contains("..") {
+      return Err(format!("invalid local part '{}'", local_part));
+    }
+  }
+
+  if pos >= chars.len() || chars[pos] != '@' {
+    if allow_bare && local_part.to_lowercase() == "postmaster" {
+      return Ok((Address { local_part: local_part, domain: "".to_string() }, pos));
+    }
+    return Err(format!("missing domain after '{}'", local_part));
+  }
+  pos = pos + 1;
+
+  let mut domain = String::new();
+  if pos < chars.len() && chars[pos] == '[' {
+    // address-literal, [192.0.2.1] or [IPv6:2001:db8::1]
+    while pos < chars.len() && chars[pos] != ']' {
+      domain.push(chars[pos]);
+      pos = pos + 1;
+    }
+    if pos == chars.len() {
+      return Err("unterminated address literal".to_string());
+    }
+    domain.push(']');
+    pos = pos + 1;
+  } else {
+    while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '-' || chars[pos] == '.') {
+      domain.push(chars[pos]);
+      pos = pos + 1;
+    }
+    if domain.len() == 0 || domain.starts_with('.') || domain.contains("..") {
+      return Err(format!("invalid domain '{}'", domain));
+    }
+    // a trailing dot denotes the root, it is the same domain
+    if domain.ends_with('.') {
+      domain.pop();
+    }
+  }
+
+  Ok((Address { local_part: local_part, domain: domain.to_lowercase() }, pos))
+}
+
+// atext from RFC 5322, the characters allowed in an atom
+fn is_atext(c: char) -> bool {
+  c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse_forward_path, parse_reverse_path, Address};
+
+  fn forward(arg: &str) -> String {
+    parse_forward_path(arg).unwrap().address.unwrap().normalized()
+  }
+
+  fn reverse(arg: &str) -> String {
+    parse_reverse_path(arg).unwrap().address.unwrap().normalized()
+  }
+
+  #[test]
+  fn plain_address() {
+    assert_eq!(forward("<John.Doe@Example.COM>"), "john.doe@example.com");
+    assert_eq!(forward(" <john@example.com>"), "john@example.com");
+    assert_eq!(forward("john@example.com"), "john@example.com");
+  }
+
+  #[test]
+  fn quoted_local_part() {
+    assert_eq!(forward("<\"John Doe\"@example.com>"), "john doe@example.com");
+    assert_eq!(forward("<\"a\\\"b\\\\c\"@example.com>"), "a\"b\\c@example.com");
+    // quotes that are not needed make no difference
+    assert_eq!(forward("<\"john\"@example.com>"), forward("<john@example.com>"));
+    let address = parse_forward_path("<\"John Doe\"@example.com>").unwrap().address.unwrap();
+    assert_eq!(address.to_string(), "\"John Doe\"@example.com");
+    assert!(parse_forward_path("<\"john@example.com>").is_err());
+  }
+
+  #[test]
+  fn source_route_is_ignored() {
+    assert_eq!(forward("<@a.example,@b.example:John@C.example>"), "john@c.example");
+    assert!(parse_forward_path("<@a.example,@b.example>").is_err());
+  }
+
+  #[test]
+  fn null_path() {
+    assert!(parse_reverse_path("<>").unwrap().address.is_none());
+    assert!(parse_forward_path("<>").is_err());
+  }
+
+  #[test]
+  fn bare_postmaster() {
+    assert_eq!(forward("<Postmaster>"), "postmaster");
+    assert!(parse_reverse_path("<postmaster>").is_err());
+    assert!(parse_forward_path("<john>").is_err());
+  }
+
+  #[test]
+  fn address_literal() {
+    assert_eq!(forward("<john@[192.0.2.1]>"), "john@[192.0.2.1]");
+    assert_eq!(forward("<john@[IPv6:2001:DB8::1]>"), "john@[ipv6:2001:db8::1]");
+    assert!(parse_forward_path("<john@[192.0.2.1>").is_err());
+  }
+
+  #[test]
+  fn trailing_dot_domain() {
+    assert_eq!(forward("<john@example.com.>"), "john@example.com");
+    assert!(parse_forward_path("<john@example..com>").is_err());
+    assert!(parse_forward_path("<john@.example.com>").is_err());
+  }
+
+  #[test]
+  fn invalid_local_part() {
+    assert!(parse_forward_path("<john..doe@example.com>").is_err());
+    assert!(parse_forward_path("<.john@example.com>").is_err());
+    assert!(parse_forward_path("<john.@example.com>").is_err());
+    assert!(parse_forward_path("<john@example.com").is_err());
+  }
+
+  #[test]
+  fn parameters() {
+    let path = parse_reverse_path("<john@example.com> size=1000 BODY=8BITMIME  SMTPUTF8").unwrap();
+    assert_eq!(path.parameter("SIZE"), Some(&Some("1000".to_string())));
+    assert_eq!(path.parameter("BODY"), Some(&Some("8BITMIME".to_string())));
+    assert_eq!(path.parameter("SMTPUTF8"), Some(&None));
+    assert_eq!(path.parameter("RET"), None);
+    let null = parse_reverse_path("<> SIZE=20").unwrap();
+    assert!(null.address.is_none());
+    assert_eq!(null.parameter("SIZE"), Some(&Some("20".to_string())));
+    assert_eq!(reverse("<john@example.com> BODY=BINARYMIME"), "john@example.com");
+    assert!(parse_reverse_path("<john@example.com>SIZE=1000").is_err());
+    assert!(parse_reverse_path("<john@example.com> =1000").is_err());
+  }
+
+  #[test]
+  fn configured_address() {
+    assert_eq!(Address::parse("Archive@Example.com").unwrap().normalized(), "archive@example.com");
+    assert!(Address::parse("archive@example.com trailing").is_err());
+  }
+}
@@ -0,0 +1,78 @@
+//
+// Registry of the ESMTP extensions mail-archiver knows how to speak;
+// the ones enabled in the configuration are advertised in the EHLO
+// response, see RFC 5321 section 4.1.1.1.
+#[derive(Clone, PartialEq)]
+pub enum Capability {
+  EnhancedStatusCodes,   // RFC 2034
+  Pipelining,            // RFC 2920
+  Size,                  // RFC 1870, carries max_message_size
+  EightBitMime,          // RFC 6152
+  Chunking,              // RFC 3030, BDAT
+  BinaryMime,            // RFC 3030, needs CHUNKING
+  StartTls,              // RFC 3207, only advertised with a TLS identity
+}
+
+impl Capability {
+
+  // Look up an extension by its EHLO keyword, as used in the configuration
+  pub fn from_name(name: &str) -> Option<Capability> {
+    match name.to_uppercase().as_str() {
+      "ENHANCEDSTATUSCODES" => Some(Capability::EnhancedStatusCodes),
+      "PIPELINING" => Some(Capability::Pipelining),
+      "SIZE" => Some(Capability::Size),
+      "8BITMIME" => Some(Capability::EightBitMime),
+      "CHUNKING" => Some(Capability::Chunking),
+      "BINARYMIME" => Some(Capability::BinaryMime),
+      "STARTTLS" => Some(Capability::StartTls),
+      _ => None,
+    }
+  }
+
+  // The line advertised in the EHLO response
+  pub fn keyword(&self) -> String {
+    match *self {
+      Capability::EnhancedStatusCodes => "ENHANCEDSTATUSCODES".to_string(),
+      Capability::Pipelining => "PIPELINING".to_string(),
+      Capability::Size => "SIZE".to_string(),
+      Capability::EightBitMime => "8BITMIME".to_string(),
+      Capability::Chunking => "CHUNKING".to_string(),
+      Capability::BinaryMime => "BINARYMIME".to_string(),
+      Capability::StartTls => "STARTTLS".to_string(),
+    }
+  }
+}
+
+// Extensions advertised when the configuration does not list any
+pub fn default_capabilities() -> Vec<Capability> {
+  vec![Capability::EnhancedStatusCodes, Capability::Pipelining, Capability::Size, Capability::EightBitMime, Capability::Chunking,
+       Capability::BinaryMime, Capability::StartTls]
+}
+
+// Build the, possibly multi-line, 250 response to EHLO: the first line
+// carries the server name, each following line an extension keyword.
+// SIZE without a number means there is no fixed limit.
+pub fn ehlo_response(servername: &str, capabilities: &[Capability], max_message_size: Option<u64>) -> String {
+  let mut lines = vec![servername.to_string()];
+  for c in capabilities.iter() {
+    match (c, max_message_size) {
+      (&Capability::Size, Some(max)) => lines.push(format!("{} {}", c.keyword(), max)),
+      _ => lines.push(c.keyword()),
+    }
+  }
+  multiline_response(250, &lines)
+}
+
+// Join lines as "250-first\r\n250-second\r\n250 last", the codec adds the
+// final line terminator.
+pub fn multiline_response(code: u16, lines: &[String]) -> String {
+  let mut response = String::new();
+  for (i, line) in lines.iter().enumerate() {
+    if i + 1 < lines.len() {
+      response.push_str(&format!("{}-{}\r\n", code, line));
+    } else {
+      response.push_str(&format!("{} {}", code, line));
+    }
+  }
+  response
+}
@@ -7,60 +7,111 @@ extern crate uuid;
 
 
 use std::io;
-use std::fs::File;
-use std::fs;
-use std::io::{Write, Seek, SeekFrom};
+use std::cmp;
+use std::mem;
+use std::rc::Rc;
+use std::net::IpAddr;
 use tokio_core::io::{Codec, EasyBuf};
 use encoding::{Encoding, DecoderTrap, EncoderTrap};
 use tokio_core::io::{Framed, Io};
 use tokio_proto::pipeline::ServerProto;
 use encoding::all::ASCII;
-use futures::{IntoFuture, Future, Sink, Stream};
+use futures::{Async, AsyncSink, IntoFuture, Future, Poll, Sink, Stream};
 use time;
 use uuid::Uuid;
+use native_tls::TlsAcceptor;
+use tokio_tls::{TlsAcceptorExt, TlsStream};
 
 use config;
+use address::{self, Address};
+use pattern;
+use template;
+use sha2::{Digest, Sha256};
+use storage::{self, Delivery, Envelope, SpoolError};
+use capability::{self, Capability};
+use command::{self, Command};
+use tls;
 
-pub struct ASCIILineBased;
+// What the codec hands to the state machine: command lines, or the raw
+// octets of the message while in DATA.
+pub enum Frame {
+  Command(String),
+  Data(Vec<u8>),   // one line of message data, as received but dot-unstuffed
+  DataEnd,         // the terminating "."
+  Chunk(Vec<u8>),  // octets following a BDAT command, RFC 3030
+  ChunkEnd,        // all octets announced by BDAT have been read
+}
+
+// Line based codec; commands are decoded as ASCII, but after a 354 reply
+// has been sent the codec is in data mode and passes lines through
+// untouched, so 8-bit and UTF-8 messages are archived byte for byte.
+// Only CRLF ends a line, RFC 5321 2.3.8; a bare CR or LF is just data.
+pub struct SmtpCodec {
+  data_mode: bool,
+  line_start: bool,   // in data mode, the next octet starts a line
+  chunk_remaining: Option<u64>,  // octets left of the current BDAT chunk
+}
+
+// longest command line accepted, RFC 5321 4.5.3.1.4 plus room for parameters
+const MAX_COMMAND_LINE: usize = 2048;
+
+// message data without CRLF is passed on in pieces of this size
+const MAX_DATA_PIECE: usize = 65536;
+
+impl SmtpCodec {
+  pub fn new() -> SmtpCodec {
+    SmtpCodec { data_mode: false, line_start: true, chunk_remaining: None }
+  }
+}
 
-impl Codec for ASCIILineBased {
-  type In = String;
+impl Codec for SmtpCodec {
+  type In = Frame;
   type Out = String;
 
   // Read a line from the wire
   //
-  fn decode(&mut self, buf: &mut EasyBuf) -> Result<Option<String>, io::Error> {
+  fn decode(&mut self, buf: &mut EasyBuf) -> Result<Option<Frame>, io::Error> {
+    if self.chunk_remaining.is_some() {
+      return Ok(self.decode_chunk(buf));
+    }
+    if self.data_mode {
+      return Ok(self.decode_data(buf));
+    }
     // read lines...
-   if let Some(i) = buf.as_slice().iter().position(|&b| b == b'\n' || b == b'\r') {
-        // remove the serialized frame from the buffer.
+    match find_crlf(buf.as_slice()) {
+      Some(i) => {
+        // remove the serialized frame from the buffer, and the CRLF
         let line = buf.drain_to(i);
-
-        // Also remove the '\n' / '\r'
-        let crlf_buf = buf.drain_to(1);
-        let crlf = crlf_buf.as_slice();
-
-        if buf.len() > 0 {
-            // check if next char is a \n or \r
-            if crlf[0] == b'\r' && buf.as_slice()[0] == b'\n' {
-               buf.drain_to(1);
-            }
-            if crlf[0] == b'\n' && buf.as_slice()[0] == b'\r' {
-               buf.drain_to(1);
-            }
-        }
+        buf.drain_to(2);
 
         // we have a line and can return it
-        return match ASCII.decode(line.as_slice(), DecoderTrap::Ignore) {
-          Ok(str) => Ok(Some(str)),
+        match ASCII.decode(line.as_slice(), DecoderTrap::Ignore) {
+          Ok(str) => {
+            // the chunk follows the command right away, whatever our reply
+            if let Some((size, _)) = command::parse_bdat(&str) {
+              self.chunk_remaining = Some(size);
+            }
+            Ok(Some(Frame::Command(str)))
+          },
           Err(_) => Err(io::Error::new(io::ErrorKind::Other, "invalid string")),
         }
+      },
+      None if buf.len() > MAX_COMMAND_LINE => {
+        Err(io::Error::new(io::ErrorKind::Other, "command line too long"))
+      },
+      None => Ok(None),
     }
-    Ok(None)
   }
 
   // Encode a line over the wire
   //
   fn encode (&mut self, data: String, buf: &mut Vec<u8>) -> io::Result<()> {
+    // the client sends the message right after "354", the state machine
+    // cannot reach the codec inside Framed, so switch mode here
+    if data.starts_with("354") {
+      self.data_mode = true;
+      self.line_start = true;
+    }
     match ASCII.encode(&data, EncoderTrap::Ignore) {
       Ok(bytes) => buf.extend(bytes.iter()),
       Err(_) => ()
@@ -70,46 +121,182 @@ impl Codec for ASCIILineBased {
   }
 }
 
+impl SmtpCodec {
+
+  // Read one line of message data, keeping its CRLF; the data ends with
+  // CRLF "." CRLF, i.e. a line of just "." that follows a CRLF.
+  fn decode_data(&mut self, buf: &mut EasyBuf) -> Option<Frame> {
+    let line = match find_crlf(buf.as_slice()) {
+      Some(i) => buf.drain_to(i + 2),
+      None if buf.len() > MAX_DATA_PIECE => {
+        // a very long line, pass on a piece but keep a trailing CR back
+        // as it may be the start of the CRLF
+        let mut n = buf.len();
+        if buf.as_slice()[n - 1] == b'\r' {
+          n = n - 1;
+        }
+        let piece = buf.drain_to(n);
+        let at_start = self.line_start;
+        self.line_start = false;
+        return Some(Frame::Data(unstuff(piece.as_slice(), at_start)));
+      },
+      None => return None,
+    };
+    let at_start = self.line_start;
+    self.line_start = true;
+    if at_start && line.as_slice() == b".\r\n" {
+      self.data_mode = false;
+      return Some(Frame::DataEnd);
+    }
+    Some(Frame::Data(unstuff(line.as_slice(), at_start)))
+  }
+}
+
+impl SmtpCodec {
+
+  // Pass on the octets of a BDAT chunk exactly as they are
+  fn decode_chunk(&mut self, buf: &mut EasyBuf) -> Option<Frame> {
+    let remaining = self.chunk_remaining.unwrap();
+    if remaining == 0 {
+      self.chunk_remaining = None;
+      return Some(Frame::ChunkEnd);
+    }
+    if buf.len() == 0 {
+      return None;
+    }
+    let n = cmp::min(cmp::min(remaining, buf.len() as u64), MAX_DATA_PIECE as u64) as usize;
+    let piece = buf.drain_to(n);
+    self.chunk_remaining = Some(remaining - n as u64);
+    Some(Frame::Chunk(piece.as_slice().to_vec()))
+  }
+}
+
+// Transparency, RFC 5321 4.5.2: the client doubled any dot that starts a line
+fn unstuff(bytes: &[u8], at_line_start: bool) -> Vec<u8> {
+  if at_line_start && bytes.len() > 0 && bytes[0] == b'.' {
+    bytes[1..].to_vec()
+  } else {
+    bytes.to_vec()
+  }
+}
+
+// Position of the first CRLF
+fn find_crlf(bytes: &[u8]) -> Option<usize> {
+  bytes.windows(2).position(|w| w == b"\r\n")
+}
+
+// An archive the current message goes to
+pub struct Destination {
+  archive_path: String,     // templates, with envelope variables filled in
+  file_name: String,
+  storage: config::StorageType,
+  compression: config::Compression,
+  encryption_key: Option<Vec<u8>>,
+  delivery: Option<Box<Delivery>>,   // while the message is being stored
+  stored: Option<String>,   // where the message ended up, None if it did not
+}
+
 //
 // Struct to hold data about email being consumed
 // mailData is not suppossed to hold full email, strategy is:
-//  mailData holds up to "Message-Id" header, then mailFile
-//  is created and all mailData is written there.
-// When mailFile is established then mailData is flushed to 
-//  mailFile for every 100 lines, or when mail is done.
-// If no MessageId is identified and all headers are read
-//  then mailFile is created by using a Random UUID filename
+//  mailData holds the headers, then, once rules have added their
+//  destinations, a delivery is begun in each destination and all
+//  mailData is written there.
+// When deliveries are established then mailData is flushed to 
+//  them for every 64 lines, or when mail is done.
+// If no MessageId is identified in the headers
+//  then the deliveries are named by a Random UUID
 pub struct EmailData {
+  servername: String,
+  capabilities: Vec<Capability>,
+  tls_acceptor: Option<Rc<TlsAcceptor>>,  // None when no TLS identity is configured
+  tls_active: bool,
+  require_tls: bool,
   client_helo: String,
-  mail_from: String,
-  archive_path: String,
-  rcpt_to: Vec<String>,
-  mail_data: Vec<String>,   // mail data lines
-  mail_file: Option<File>,          // mail backup file
+  client_addr: Option<IpAddr>,      // None on local sockets
+  mail_from: Option<Address>,   // None is the null sender
+  message_id: Option<(String, String)>,   // as given, and made safe for a file name
+  uuid: String,
+  destinations: Vec<Destination>,
+  rcpt_to: Vec<Address>,
+  mail_data: Vec<Vec<u8>>,   // mail data lines, with line terminators
+  partial_line: Vec<u8>,    // BDAT chunks need not end on a line boundary
+  chunking: bool,           // message is being sent with BDAT
+  body_binary: bool,        // BODY=BINARYMIME, DATA is not allowed
+  spooling: bool,                   // deliveries have begun
+  spool_error: Option<SpoolError>,  // storing failed, the message is not kept
+  max_message_size: Option<u64>,    // global limit, None is unlimited
+  size_limit: Option<u64>,          // limit for this message, per recipients
+  declared_size: Option<u64>,       // SIZE= given with MAIL FROM
+  message_size: u64,                // octets received so far
+  oversized: bool,
+  in_headers: bool,
+  line_start: bool,                 // the next message data pushed starts a line
+  headers: Vec<(String, String)>,   // lower case name and unfolded value
+  matched_rules: Vec<String>,
+  unknown_recipients: config::UnknownRecipients,
+  rules: Vec<config::RuleSetup>,
   datetime: time::Tm,
   prefix: String,
   archivers: Vec<config::ArchiverSetup>,
 }
 
 pub fn clear_emaildata(mut md: EmailData) -> EmailData {
-  md.mail_from = "".to_string();
-  md.archive_path = "".to_string();
+  md.mail_from = None;
+  md.message_id = None;
+  md.uuid = Uuid::new_v4().hyphenated().to_string();
+  md.destinations = Vec::new();
   md.rcpt_to = Vec::new();
   md.mail_data = Vec::new();
-  md.mail_file = None;
+  md.partial_line = Vec::new();
+  md.chunking = false;
+  md.body_binary = false;
+  md.spooling = false;
+  md.spool_error = None;
+  md.size_limit = md.max_message_size;
+  md.declared_size = None;
+  md.message_size = 0;
+  md.oversized = false;
+  md.in_headers = true;
+  md.line_start = true;
+  md.headers = Vec::new();
+  md.matched_rules = Vec::new();
   md.datetime = time::empty_tm();
   md
 }
 
 
-pub fn make_emaildata(prefix: String, archivers: Vec<config::ArchiverSetup>) -> EmailData {
+pub fn make_emaildata(prefix: String, archivers: Vec<config::ArchiverSetup>, client_addr: Option<IpAddr>) -> EmailData {
   EmailData {
+    servername: "".to_string(),
+    capabilities: Vec::new(),
+    tls_acceptor: None,
+    tls_active: false,
+    require_tls: false,
     client_helo: "".to_string(),
-    mail_from: "".to_string(),
+    client_addr: client_addr,
+    mail_from: None,
+    message_id: None,
+    uuid: Uuid::new_v4().hyphenated().to_string(),
     rcpt_to: Vec::new(),
-    archive_path: "".to_string(),
+    destinations: Vec::new(),
     mail_data: Vec::new(),
-    mail_file: None,
+    partial_line: Vec::new(),
+    chunking: false,
+    body_binary: false,
+    spooling: false,
+    spool_error: None,
+    max_message_size: None,
+    size_limit: None,
+    declared_size: None,
+    message_size: 0,
+    oversized: false,
+    in_headers: true,
+    line_start: true,
+    headers: Vec::new(),
+    matched_rules: Vec::new(),
+    unknown_recipients: config::UnknownRecipients::Reject,
+    rules: Vec::new(),
     datetime: time::empty_tm(),
     archivers: archivers,
     prefix: prefix,
@@ -122,16 +309,16 @@ pub trait Chatty<T: 'static>: ServerProto<T> {
 
     fn map_future(fut: Box<Future<Item = Self::Transport, Error = io::Error>>) -> Self::BindTransport;
 
-    // Send a response, then launch next step
+    // Queue a response, then launch next step; it is sent by await_line
     fn send_line(transport: Self::Transport, st: Self::State, response: Self::Response, nextstep: Box<Fn(Self::Transport, Self::State) -> Self::BindTransport>) -> Self::BindTransport {
-       let hs = Box::new(transport.send(response).into_future().map_err(|e| e)
+       let hs = Box::new(QueueLine { transport: Some(transport), response: Some(response) }
             .and_then(move |tx| nextstep(tx, st))) as Box<Future<Item = Self::Transport, Error = io::Error>>;
        Self::map_future(hs)
     }
 
     // await the response
     fn await_line(transport: Self::Transport, st: Self::State, action: Box<Fn(Self::Transport, Self::Request, Self::State) -> Self::BindTransport>) -> Self::BindTransport {
-      let hs = Box::new(transport.into_future().map_err(|(e, _)| e).and_then(move |(line, tx)| {
+      let hs = Box::new(NextLine { transport: Some(transport) }.and_then(move |(line, tx)| {
         match line {
           Some(msg) => action(tx, msg, st),
           None => Self::map_future(Box::new(tx.into_future().map_err(|(e, _)| e).and_then(|_| Err(io::Error::new(io::ErrorKind::Other, "Got bad data"))))),
@@ -153,37 +340,134 @@ pub trait Chatty<T: 'static>: ServerProto<T> {
    
 }
 
+// Future for Chatty::send_line, puts the response in the write buffer of
+// the transport without flushing it
+struct QueueLine<S: Sink> {
+  transport: Option<S>,
+  response: Option<S::SinkItem>,
+}
+
+impl<S: Sink> Future for QueueLine<S> {
+  type Item = S;
+  type Error = S::SinkError;
+
+  fn poll(&mut self) -> Poll<S, S::SinkError> {
+    {
+      let transport = self.transport.as_mut().expect("QueueLine polled after completion");
+      let response = self.response.take().unwrap();
+      if let AsyncSink::NotReady(response) = try!(transport.start_send(response)) {
+        // write buffer is full, flush what we can and try again later
+        self.response = Some(response);
+        try!(transport.poll_complete());
+        return Ok(Async::NotReady);
+      }
+    }
+    Ok(Async::Ready(self.transport.take().unwrap()))
+  }
+}
+
+// Future for Chatty::await_line, yields the next request. Queued responses
+// are only flushed when no further request is buffered already; that way
+// a batch of pipelined commands gets its replies in one write, RFC 2920.
+struct NextLine<S> {
+  transport: Option<S>,
+}
+
+impl<S: Stream + Sink<SinkError = <S as Stream>::Error>> Future for NextLine<S> {
+  type Item = (Option<S::Item>, S);
+  type Error = <S as Stream>::Error;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    let line = {
+      let transport = self.transport.as_mut().expect("NextLine polled after completion");
+      match try!(transport.poll()) {
+        Async::Ready(line) => line,
+        Async::NotReady => {
+          // client awaits our replies
+          try!(transport.poll_complete());
+          return Ok(Async::NotReady);
+        },
+      }
+    };
+    Ok(Async::Ready((line, self.transport.take().unwrap())))
+  }
+}
+
 pub struct SmtpProto {
   archivers: Vec<config::ArchiverSetup>,
   servername: String,
+  capabilities: Vec<Capability>,
+  tls_acceptor: Option<Rc<TlsAcceptor>>,
+  require_tls: bool,
+  max_message_size: Option<u64>,
+  unknown_recipients: config::UnknownRecipients,
+  rules: Vec<config::RuleSetup>,
 }
 
 impl<T: Io + 'static> ServerProto<T> for SmtpProto {
-  type Request = String;
+  type Request = Frame;
   type Response = String;
 
-  type Transport = Framed<T, ASCIILineBased>;
+  type Transport = Framed<tls::Stream<T>, SmtpCodec>;
   type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
 
   fn bind_transport(&self, io: T) -> Self::BindTransport {
-    let transport = io.framed(ASCIILineBased);
-    let md = make_emaildata("none".to_string(), self.archivers.clone());
-    Self::greet(transport, md, self.servername.clone())
+    let transport = tls::Stream::Plain(io).framed(SmtpCodec::new());
+    let md = self.setup_emaildata(make_emaildata("none".to_string(), self.archivers.clone(), None));
+    Self::greet(transport, md)
   }
 }
 
 impl SmtpProto {
 
-  pub fn bind_transport<T>(&self, io: T, md: EmailData) -> <Self as ServerProto<T>>::BindTransport 
+  pub fn bind_transport<T>(&self, io: T, md: EmailData, mode: config::TlsMode) -> <Self as ServerProto<T>>::BindTransport 
    where T: Io + 'static {
-     let transport = io.framed(ASCIILineBased);
-     Self::greet(transport, md, self.servername.clone())
+     let mut md = self.setup_emaildata(md);
+     match (mode, md.tls_acceptor.clone()) {
+       (config::TlsMode::Implicit, Some(acceptor)) => {
+         // SMTPS, handshake before the greeting
+         Box::new(acceptor.accept_async(io)
+           .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("TLS handshake failed: {}", e)))
+           .and_then(move |stream| {
+             md.tls_active = true;
+             let transport = tls::Stream::Tls(stream).framed(SmtpCodec::new());
+             Self::greet(transport, md)
+           }))
+       },
+       (config::TlsMode::Implicit, None) => {
+         // never fall back to plain text on an SMTPS port
+         Self::fail::<T>("No TLS identity for implicit TLS listener")
+       },
+       (config::TlsMode::None, _) => {
+         md.tls_acceptor = None;
+         let transport = tls::Stream::Plain(io).framed(SmtpCodec::new());
+         Self::greet(transport, md)
+       },
+       (config::TlsMode::StartTls, _) => {
+         let transport = tls::Stream::Plain(io).framed(SmtpCodec::new());
+         Self::greet(transport, md)
+       },
+     }
   }
 
-  pub fn new (servername: String, archivers: Vec<config::ArchiverSetup>) -> SmtpProto {
-    SmtpProto { servername: servername, archivers: archivers }
+  pub fn new (servername: String, archivers: Vec<config::ArchiverSetup>, capabilities: Vec<Capability>) -> SmtpProto {
+    SmtpProto { servername: servername, archivers: archivers, capabilities: capabilities, tls_acceptor: None, require_tls: false, max_message_size: None,
+                unknown_recipients: config::UnknownRecipients::Reject, rules: Vec::new() }
   } 
 
+  // copy the per server settings to the state of a new connection
+  fn setup_emaildata (&self, mut md: EmailData) -> EmailData {
+    md.servername = self.servername.clone();
+    md.capabilities = self.capabilities.clone();
+    md.tls_acceptor = self.tls_acceptor.clone();
+    md.require_tls = self.require_tls;
+    md.max_message_size = self.max_message_size;
+    md.size_limit = self.max_message_size;
+    md.unknown_recipients = self.unknown_recipients.clone();
+    md.rules = self.rules.clone();
+    md
+  }
+
   pub fn set_archivers (&mut self, archivers: Vec<config::ArchiverSetup>) -> () {
     self.archivers = archivers;
   }
@@ -192,6 +476,27 @@ impl SmtpProto {
     self.servername = servername;
   }
 
+  pub fn set_capabilities (&mut self, capabilities: Vec<Capability>) -> () {
+    self.capabilities = capabilities;
+  }
+
+  pub fn set_max_message_size (&mut self, max_message_size: Option<u64>) -> () {
+    self.max_message_size = max_message_size;
+  }
+
+  pub fn set_unknown_recipients (&mut self, unknown_recipients: config::UnknownRecipients) -> () {
+    self.unknown_recipients = unknown_recipients;
+  }
+
+  pub fn set_rules (&mut self, rules: Vec<config::RuleSetup>) -> () {
+    self.rules = rules;
+  }
+
+  pub fn set_tls (&mut self, tls_acceptor: Option<Rc<TlsAcceptor>>, require_tls: bool) -> () {
+    self.tls_acceptor = tls_acceptor;
+    self.require_tls = require_tls;
+  }
+
 /*
   pub fn lookup_archivepath (&mut self, recipient: String) -> Option<String> {
     for m in self.archivers.iter() {
@@ -217,173 +522,701 @@ impl<T: Io + 'static> Chatty<T> for SmtpProto {
 
 impl SmtpProto {
 
-  fn greet<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, md: <Self as Chatty<T>>::State, servername: String) -> <Self as ServerProto<T>>::BindTransport {
+  fn greet<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, md: <Self as Chatty<T>>::State) -> <Self as ServerProto<T>>::BindTransport {
     debug!("Connection from {} sending 220 greeting", "clientname");
-    Self::send_line(tx, md, format!("220 {}", servername), Box::new(Self::wait_for_client_helo))
+    let greeting = format!("220 {} ESMTP", md.servername);
+    Self::send_line(tx, md, greeting, Box::new(Self::wait_for_client_helo))
   }
 
   fn respond_to_quit<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport) -> <Self as ServerProto<T>>::BindTransport {
-    Box::new(tx.send("221 Bye".to_string()).and_then(|_| Err(io::Error::new(io::ErrorKind::Other, "Client closed"))))
+    Box::new(tx.send("221 2.0.0 Bye".to_string()).and_then(|_| Err(io::Error::new(io::ErrorKind::Other, "Client closed"))))
     // in tokio-core 0.2 we'll have the opportunity to signal connection shutdown
   }
 
+  // drop the connection
+  fn fail<T: Io + 'static> (reason: &str) -> <Self as ServerProto<T>>::BindTransport {
+    Box::new(Err(io::Error::new(io::ErrorKind::Other, reason.to_string())).into_future())
+  }
+
+  // Answer 220 in plain text, then do the TLS handshake on the raw stream
+  // and frame it again; the client starts over with EHLO, RFC 3207 4.2.
+  fn start_tls<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, mut md: <Self as Chatty<T>>::State) -> <Self as ServerProto<T>>::BindTransport {
+    let acceptor = md.tls_acceptor.clone().unwrap();
+    Box::new(tx.send("220 2.0.0 Ready to start TLS".to_string()).and_then(move |tx| {
+      match tx.into_inner() {
+        tls::Stream::Plain(io) => {
+          Box::new(acceptor.accept_async(io).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("TLS handshake failed: {}", e))))
+            as Box<Future<Item = TlsStream<T>, Error = io::Error>>
+        },
+        tls::Stream::Tls(_) => {
+          Box::new(Err(io::Error::new(io::ErrorKind::Other, "TLS already active")).into_future())
+        },
+      }
+    }).and_then(move |stream| {
+      debug!("TLS handshake done, awaiting new EHLO");
+      md.tls_active = true;
+      md.client_helo = "".to_string();
+      let transport = tls::Stream::Tls(stream).framed(SmtpCodec::new());
+      Self::wait_for_client_helo(transport, clear_emaildata(md))
+    }))
+  }
+
+  // await a command line; message data is only expected after 354
+  fn await_command<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, md: <Self as Chatty<T>>::State, action: Box<Fn(<Self as ServerProto<T>>::Transport, String, <Self as Chatty<T>>::State) -> <Self as ServerProto<T>>::BindTransport>) -> <Self as ServerProto<T>>::BindTransport {
+    Self::await_command_rc(tx, md, Rc::new(action))
+  }
+
+  fn await_command_rc<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, md: <Self as Chatty<T>>::State, action: Rc<Box<Fn(<Self as ServerProto<T>>::Transport, String, <Self as Chatty<T>>::State) -> <Self as ServerProto<T>>::BindTransport>>) -> <Self as ServerProto<T>>::BindTransport {
+    Self::await_line(tx, md, Box::new(move |tx, frame, st| {
+      match frame {
+        Frame::Command(line) => action(tx, line, st),
+        // the chunk of a rejected BDAT, it is read and thrown away
+        Frame::Chunk(_) | Frame::ChunkEnd => Self::await_command_rc(tx, st, action.clone()),
+        _ => Self::fail::<T>("Got message data outside DATA"),
+      }
+    }))
+  }
+
+  // Throw away the transaction, and what has been spooled of it
+  fn reset (md: EmailData) -> EmailData {
+    clear_emaildata(Self::discard_file(md))
+  }
+
+  // Commands that mean the same in every state, RFC 5321 4.1.4; the rest
+  // is handed back for the state to answer. `again` is the state to stay in.
+  fn any_state<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, st: <Self as Chatty<T>>::State, command: Command, again: fn(<Self as ServerProto<T>>::Transport, <Self as Chatty<T>>::State) -> <Self as ServerProto<T>>::BindTransport)
+      -> Result<<Self as ServerProto<T>>::BindTransport, (<Self as ServerProto<T>>::Transport, <Self as Chatty<T>>::State, Command)> {
+    match command {
+      // a new HELO/EHLO implies RSET
+      Command::Helo(name) => {
+        let mut st = Self::reset(st);
+        st.client_helo = name;
+        let response = format!("250 {}", st.servername);
+        Ok(Self::send_line(tx, st, response, Box::new(Self::wait_for_mail_from)))
+      },
+      Command::Ehlo(name) => {
+        let mut st = Self::reset(st);
+        st.client_helo = name;
+        let tls_available = st.tls_acceptor.is_some() && !st.tls_active;
+        let capabilities: Vec<Capability> = st.capabilities.iter()
+          .filter(|c| **c != Capability::StartTls || tls_available)
+          .cloned().collect();
+        let response = capability::ehlo_response(&st.servername, &capabilities, st.max_message_size);
+        Ok(Self::send_line(tx, st, response, Box::new(Self::wait_for_mail_from)))
+      },
+      Command::Rset => {
+        let st = Self::reset(st);
+        if st.client_helo.len() == 0 {
+          Ok(Self::send_line(tx, st, "250 2.0.0 Ok".to_string(), Box::new(Self::wait_for_client_helo)))
+        } else {
+          Ok(Self::send_line(tx, st, "250 2.0.0 Ok".to_string(), Box::new(Self::wait_for_mail_from)))
+        }
+      },
+      Command::Noop => Ok(Self::send_line(tx, st, "250 2.0.0 Ok".to_string(), Box::new(again))),
+      Command::Vrfy(_) => {
+        Ok(Self::send_line(tx, st, "252 2.5.2 Cannot VRFY user, but will accept message and attempt delivery".to_string(), Box::new(again)))
+      },
+      Command::Help => {
+        Ok(Self::send_line(tx, st, "214 2.0.0 Commands: HELO EHLO MAIL RCPT DATA BDAT RSET NOOP VRFY HELP QUIT STARTTLS".to_string(), Box::new(again)))
+      },
+      Command::Quit => Ok(Self::respond_to_quit(tx)),
+      // its chunk is still read, and thrown away by await_command
+      Command::Bdat(_, _) if !st.capabilities.contains(&Capability::Chunking) => {
+        Ok(Self::send_line(tx, st, "502 5.5.1 CHUNKING not available".to_string(), Box::new(again)))
+      },
+      Command::Invalid(reason) => Ok(Self::send_line(tx, st, format!("501 5.5.4 {}", reason), Box::new(again))),
+      Command::Unknown(verb) => {
+        debug!("Unrecognized command {}", verb);
+        Ok(Self::send_line(tx, st, "500 5.5.2 Command not recognized".to_string(), Box::new(again)))
+      },
+      command => Err((tx, st, command)),
+    }
+  }
+
   fn wait_for_client_helo<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, md: <Self as Chatty<T>>::State) -> <Self as ServerProto<T>>::BindTransport {
-    Self::await_line(tx, md, Box::new(move |tx,line,mut st| {
-         if line.starts_with("HELO") {
-           st.client_helo = line;
-           Self::send_line(tx, st, "250 Ok".to_string(), Box::new(Self::wait_for_mail_from))
-         } else if line.starts_with("EHLO") {
-           st.client_helo = line;
-           Self::send_line(tx, st, "250 Ok".to_string(), Box::new(Self::wait_for_mail_from))
-         } else if line.starts_with("QUIT") {
-           Self::respond_to_quit(tx) 
-         } else {
-           Self::send_line(tx, st, "502 invalid helo".to_string(), Box::new(Self::wait_for_client_helo))
-         }
-       }))
+    Self::await_command(tx, md, Box::new(move |tx, line, st| {
+      match Self::any_state(tx, st, command::parse(&line), Self::wait_for_client_helo::<T>) {
+        Ok(next) => next,
+        Err((tx, st, _)) => Self::send_line(tx, st, "503 5.5.1 Send HELO/EHLO first".to_string(), Box::new(Self::wait_for_client_helo)),
+      }
+    }))
   }
 
   fn wait_for_mail_from<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, md: <Self as Chatty<T>>::State) -> <Self as ServerProto<T>>::BindTransport {
-    Self::await_line(tx, md, Box::new(move |tx, line, mut st| {
-      if line.starts_with("MAIL FROM:") {
-        st.mail_from = line;
-        Self::send_line(tx, st, "250 Ok".to_string(), Box::new(Self::wait_for_rcpt_to))
-      } else if line.starts_with("QUIT") {
-        Self::respond_to_quit(tx)
-      } else {
-        Self::send_line(tx, st, "502 Invalid mail from".to_string(), Box::new(Self::wait_for_mail_from))
+    Self::await_command(tx, md, Box::new(move |tx, line, st| {
+      let (tx, mut st, command) = match Self::any_state(tx, st, command::parse(&line), Self::wait_for_mail_from::<T>) {
+        Ok(next) => return next,
+        Err(unhandled) => unhandled,
+      };
+      match command {
+        Command::StartTls if st.tls_active => {
+          Self::send_line(tx, st, "503 5.5.1 TLS already active".to_string(), Box::new(Self::wait_for_mail_from))
+        },
+        Command::StartTls if st.tls_acceptor.is_some() => Self::start_tls(tx, st),
+        Command::StartTls => {
+          Self::send_line(tx, st, "502 5.5.1 STARTTLS not available".to_string(), Box::new(Self::wait_for_mail_from))
+        },
+        Command::MailFrom(_) if st.require_tls && !st.tls_active => {
+          Self::send_line(tx, st, "530 5.7.0 Must issue a STARTTLS command first".to_string(), Box::new(Self::wait_for_mail_from))
+        },
+        Command::MailFrom(arg) => {
+          match address::parse_reverse_path(&arg) {
+            Ok(path) => {
+              // RFC 6152, BODY=8BITMIME needs nothing special as data is stored as is
+              if let Some(&Some(ref body)) = path.parameter("BODY") {
+                let body = body.to_uppercase();
+                let binary = body == "BINARYMIME" && st.capabilities.contains(&Capability::BinaryMime);
+                if body != "7BIT" && body != "8BITMIME" && !binary {
+                  return Self::send_line(tx, st, format!("501 5.5.4 Unsupported BODY type {}", body), Box::new(Self::wait_for_mail_from));
+                }
+                st.body_binary = binary;
+              }
+              // RFC 1870, refuse early what will be too big anyway
+              if let Some(size) = path.parameter("SIZE") {
+                let declared = match size.as_ref().map(|v| v.parse::<u64>()) {
+                  Some(Ok(n)) => n,
+                  _ => return Self::send_line(tx, st, "501 5.5.4 Invalid SIZE parameter".to_string(), Box::new(Self::wait_for_mail_from)),
+                };
+                if st.max_message_size.map_or(false, |max| declared > max) {
+                  return Self::send_line(tx, st, "552 5.3.4 Message size exceeds fixed maximum message size".to_string(), Box::new(Self::wait_for_mail_from));
+                }
+                st.declared_size = Some(declared);
+              }
+              st.mail_from = path.address;
+              Self::send_line(tx, st, "250 2.1.0 Ok".to_string(), Box::new(Self::wait_for_rcpt_to))
+            },
+            Err(e) => {
+              debug!("Rejecting reverse-path {}: {}", arg, e);
+              Self::send_line(tx, st, format!("501 5.1.7 Invalid address: {}", e), Box::new(Self::wait_for_mail_from))
+            }
+          }
+        },
+        _ => Self::send_line(tx, st, "503 5.5.1 Need MAIL command".to_string(), Box::new(Self::wait_for_mail_from)),
       }
     }))
   }
 
   fn wait_for_rcpt_to<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, md: <Self as Chatty<T>>::State) -> <Self as ServerProto<T>>::BindTransport {
-    Self::await_line(tx, md, Box::new(move |tx, line, mut st| {
-      if line.starts_with("RCPT TO:") {
-        st.rcpt_to.push(line.clone());
-        let (_,trimmed) = line.split_at(9);
-        // lookup archive path
-        for m in st.archivers.iter() {
-          if m.recipient == trimmed {
-            st.archive_path = m.archive_path.clone();
-            debug!("Setting archive path for recipient {} to {}", trimmed, m.archive_path);
+    Self::await_command(tx, md, Box::new(move |tx, line, st| {
+      let (tx, mut st, command) = match Self::any_state(tx, st, command::parse(&line), Self::wait_for_rcpt_to::<T>) {
+        Ok(next) => return next,
+        Err(unhandled) => unhandled,
+      };
+      match command {
+        Command::RcptTo(arg) => {
+          let recipient = match address::parse_forward_path(&arg) {
+            Ok(path) => path.address.unwrap(),  // forward paths are never null
+            Err(e) => {
+              debug!("Rejecting forward-path {}: {}", arg, e);
+              return Self::send_line(tx, st, format!("501 5.1.3 Invalid address: {}", e), Box::new(Self::wait_for_rcpt_to));
+            }
+          };
+          // lookup archive path
+          let normalized = recipient.normalized();
+          let mut size_limit = st.size_limit;
+          let archivers = st.archivers.clone();
+          let matches: Vec<(&config::ArchiverSetup, Vec<(String, String)>)> = archivers.iter()
+            .filter_map(|m| m.recipient.as_ref().and_then(|pattern| pattern.matches(&normalized)).map(|captures| (m, captures)))
+            .collect();
+          // every archiver must take the message before any is added
+          for &(m, _) in matches.iter() {
+            if let (Some(max), Some(declared)) = (m.max_message_size, st.declared_size) {
+              if declared > max {
+                debug!("Declared size {} exceeds limit {} for recipient {}", declared, max, recipient);
+                return Self::send_line(tx, st, "552 5.3.4 Message too big for recipient".to_string(), Box::new(Self::wait_for_rcpt_to));
+              }
+            }
           }
-        }
-        Self::send_line(tx, st, "250 Ok".to_string(), Box::new(Self::wait_for_rcpt_to))
-      } else
-      if line.starts_with("DATA") && st.rcpt_to.len() > 0 {
-        st.datetime = time::now_utc();
-        Self::send_line(tx, st, "354 End data with <CR><LF>.<CR><LF>".to_string(), Box::new(Self::get_data))
-      } else {
-        Self::send_line(tx, st, "502 Invalid command".to_string(), Box::new(Self::wait_for_rcpt_to))
+          for &(m, ref captures) in matches.iter() {
+            let values = Self::envelope_variables(&st, Some(&recipient));
+            let archive_path = template::expand(&pattern::expand(&m.archive_path, captures), &values);
+            let file_name = template::expand(&pattern::expand(&m.file_name, captures), &values);
+            Self::add_destination(&mut st, archive_path.clone(), file_name, m.storage.clone(), m.compression.clone(), m.encryption_key.clone());
+            size_limit = match (size_limit, m.max_message_size) {
+              (Some(a), Some(b)) => Some(cmp::min(a, b)),
+              (a, b) => a.or(b),
+            };
+            debug!("Adding archive path {} for recipient {}", archive_path, recipient);
+          }
+          if matches.len() == 0 && Self::apply_envelope_rules(&mut st) {
+            debug!("No archive for recipient {}, accepted by rule", recipient);
+            size_limit = st.size_limit;
+          } else if matches.len() == 0 {
+            match st.unknown_recipients.clone() {
+              config::UnknownRecipients::Reject => {
+                debug!("No archive for recipient {}, rejecting", recipient);
+                return Self::send_line(tx, st, "550 5.1.1 No such archive".to_string(), Box::new(Self::wait_for_rcpt_to));
+              },
+              config::UnknownRecipients::Defer => {
+                debug!("No archive for recipient {}, deferring", recipient);
+                return Self::send_line(tx, st, "450 4.1.1 No such archive, try again later".to_string(), Box::new(Self::wait_for_rcpt_to));
+              },
+              config::UnknownRecipients::Catchall(path) => {
+                let values = Self::envelope_variables(&st, Some(&recipient));
+                let path = template::expand(&path, &values);
+                let file_name = template::expand(template::DEFAULT_FILE_NAME, &values);
+                debug!("No archive for recipient {}, using catch-all {}", recipient, path);
+                Self::add_destination(&mut st, path, file_name, config::StorageType::Eml, config::Compression::None, None);
+              },
+            }
+          }
+          st.size_limit = size_limit;
+          st.rcpt_to.push(recipient);
+          Self::send_line(tx, st, "250 2.1.5 Ok".to_string(), Box::new(Self::wait_for_rcpt_to))
+        },
+        Command::Data | Command::Bdat(_, _) if st.rcpt_to.len() == 0 => {
+          Self::send_line(tx, st, "503 5.5.1 Need RCPT command".to_string(), Box::new(Self::wait_for_rcpt_to))
+        },
+        Command::Data if st.body_binary => {
+          Self::send_line(tx, st, "503 5.5.1 BINARYMIME requires BDAT".to_string(), Box::new(Self::wait_for_rcpt_to))
+        },
+        Command::Data => {
+          st.datetime = time::now_utc();
+          Self::send_line(tx, st, "354 End data with <CR><LF>.<CR><LF>".to_string(), Box::new(Self::get_data))
+        },
+        Command::Bdat(size, last) => {
+          st.datetime = time::now_utc();
+          st.chunking = true;
+          Self::get_chunk(tx, st, size, last)
+        },
+        Command::MailFrom(_) => {
+          Self::send_line(tx, st, "503 5.5.1 Nested MAIL command".to_string(), Box::new(Self::wait_for_rcpt_to))
+        },
+        _ => Self::send_line(tx, st, "503 5.5.1 Bad sequence of commands".to_string(), Box::new(Self::wait_for_rcpt_to)),
       }
     }))
   }
 
-
   fn get_data<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, md: <Self as Chatty<T>>::State) -> <Self as ServerProto<T>>::BindTransport {
-    Self::await_line(tx, md, Box::new(move |tx, line, mut st| {
-      if line == "." {
-        // spool data
-        // .. and close file
-        st = Self::drain_lines (st);
-        match st.mail_file {
-          Some(ref mut file) => {
-            if let Ok(bytes) = file.seek(SeekFrom::Current(0)) {
-              info!("Spooled {} bytes to file", bytes);
-            } else {
-              info!("Spooled mail data to file");
-            }
-          },
-          None => {
-            // no message id found, no distinction betw headers and body
-            // spool to some uuid v4 determined filename
-            let uuid = Uuid::new_v4().hyphenated().to_string();
-            let file = Self::make_file(&st, &uuid);
-            st.mail_file = Some(file);
-            st = Self::drain_lines(st);
+    Self::await_line(tx, md, Box::new(move |tx, frame, st| {
+      match frame {
+        Frame::Data(line) => Self::get_data(tx, Self::push_line(st, line)),
+        Frame::DataEnd => Self::end_data(tx, st),
+        _ => Self::fail::<T>("Got command inside DATA"),
+      }
+    }))
+  }
+
+  // Read the octets of one BDAT chunk, then answer it; the last chunk
+  // completes the message like the final "." of DATA.
+  fn get_chunk<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, md: <Self as Chatty<T>>::State, size: u64, last: bool) -> <Self as ServerProto<T>>::BindTransport {
+    Self::await_line(tx, md, Box::new(move |tx, frame, mut st| {
+      match frame {
+        Frame::Chunk(bytes) => {
+          if st.oversized {
+            // read, but no longer kept
+            st.partial_line.clear();
+            return Self::get_chunk(tx, st, size, last);
           }
-        };
+          // split into lines, so headers are seen just as with DATA
+          st.partial_line.extend(bytes);
+          while let Some(i) = st.partial_line.iter().position(|&b| b == b'\n') {
+            let rest = st.partial_line.split_off(i + 1);
+            let line = mem::replace(&mut st.partial_line, rest);
+            st = Self::push_line(st, line);
+          }
+          if st.partial_line.len() > MAX_DATA_PIECE {
+            // a very long line, pass it on in pieces like decode_data, so
+            // it is counted against the size limit as it arrives
+            let piece = mem::replace(&mut st.partial_line, Vec::new());
+            st = Self::push_line(st, piece);
+          }
+          Self::get_chunk(tx, st, size, last)
+        },
+        Frame::ChunkEnd if last => {
+          if st.partial_line.len() > 0 {
+            let line = mem::replace(&mut st.partial_line, Vec::new());
+            st = Self::push_line(st, line);
+          }
+          Self::end_data(tx, st)
+        },
+        Frame::ChunkEnd => {
+          Self::send_line(tx, st, format!("250 2.0.0 {} octets received", size), Box::new(Self::wait_for_bdat))
+        },
+        _ => Self::fail::<T>("Got command inside BDAT chunk"),
+      }
+    }))
+  }
 
-        let md = clear_emaildata(st);
-        Self::send_line(tx, md, "250 Ok: queued".to_string(), Box::new(Self::wait_for_mail_from))
+  // Between chunks only another BDAT will do, or RSET to give up
+  fn wait_for_bdat<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, md: <Self as Chatty<T>>::State) -> <Self as ServerProto<T>>::BindTransport {
+    Self::await_command(tx, md, Box::new(move |tx, line, st| {
+      match Self::any_state(tx, st, command::parse(&line), Self::wait_for_bdat::<T>) {
+        Ok(next) => next,
+        Err((tx, st, Command::Bdat(size, last))) => Self::get_chunk(tx, st, size, last),
+        Err((tx, st, _)) => Self::send_line(tx, st, "503 5.5.1 Expecting BDAT".to_string(), Box::new(Self::wait_for_bdat)),
+      }
+    }))
+  }
+
+  // Handle a line of message data: note the Message-ID, begin the
+  // deliveries once the headers are done, and spool the line
+  fn push_line (mut st: EmailData, line: Vec<u8>) -> EmailData {
+    if st.oversized || st.spool_error.is_some() {
+      return st;
+    }
+    st.message_size = st.message_size + line.len() as u64;
+    if st.size_limit.map_or(false, |limit| st.message_size > limit) {
+      info!("Message exceeds {} octets, discarding it", st.size_limit.unwrap());
+      st.oversized = true;
+      return Self::discard_file(st);
+    }
+    // a long line comes in pieces, only its first piece starts a line; DATA
+    // lines end with CRLF, which pieces never do, BDAT chunks are split at LF
+    let at_start = st.line_start;
+    st.line_start = if st.chunking { line.ends_with(b"\n") } else { line.ends_with(b"\r\n") };
+    let (is_messageid, is_blank, header) = {
+      let content = line_content(&line);
+      (content.len() >= 11 && content[..11].eq_ignore_ascii_case(b"message-id:"),
+       content.len() == 0,
+       String::from_utf8_lossy(content).into_owned())
+    };
+    if st.in_headers && at_start {
+      if is_blank {
+        // header done, rules may add destinations, and the file names
+        // are known; without a suitable message id the uuid is used
+        st.in_headers = false;
+        st = Self::apply_rules(st);
+        st = Self::open_spool(st);
       } else {
-        if line.starts_with("Message-ID:") {
-          match st.mail_file {
-             None => {
-               match Self::parse_messageid(&line) {
-                 None => {},
-                 Some((_messageid, safe)) => {
-                   // now make a mail file
-                   let file = Self::make_file(&st, &safe);
-                   st.mail_file = Some(file);
-                   st = Self::drain_lines(st);
-                 }
-               }
-             },
-             _ => () // we already have a mail file
-          }
-        } else
-        if line == "" {  // header done, make a decision on destination file
-          match st.mail_file {
-            Some(_) => { // all good we're spooling i
-            },
-            None => { // No suitable message id found, make uuid
-              let uuid = Uuid::new_v4().hyphenated().to_string();
-              let file = Self::make_file(&st, &uuid);
-              st.mail_file = Some(file);
-              st = Self::drain_lines(st);
-            }
+        if is_messageid && st.message_id.is_none() {
+          st.message_id = Self::parse_messageid(&header);
+        }
+        st = Self::add_header(st, &header);
+      }
+    }
+    st.mail_data.push(line);
+
+    // flush stored lines
+    if st.mail_data.len() > 64 {
+      st = Self::drain_lines(st);
+    }
+    st
+  }
+
+  fn end_data<T: Io + 'static> (tx: <Self as ServerProto<T>>::Transport, mut st: <Self as Chatty<T>>::State) -> <Self as ServerProto<T>>::BindTransport {
+    if st.oversized {
+      let md = clear_emaildata(st);
+      return Self::send_line(tx, md, "552 5.3.4 Message size exceeds fixed maximum message size".to_string(), Box::new(Self::wait_for_mail_from));
+    }
+    // a message of headers only
+    if st.in_headers {
+      st.in_headers = false;
+      st = Self::apply_rules(st);
+    }
+    if !st.spooling && st.spool_error.is_none() {
+      st = Self::open_spool(st);
+    }
+    // spool data
+    // .. and close file
+    st = Self::drain_lines (st);
+    // only answer 250 once the message is safely stored, everywhere
+    if let Err(e) = Self::commit_all(&mut st) {
+      error!("Cannot archive mail: {}", e.message);
+      let md = clear_emaildata(Self::discard_file(st));
+      return Self::send_line(tx, md, e.reply(), Box::new(Self::wait_for_mail_from));
+    }
+    info!("Archived {} bytes", st.message_size);
+    if st.matched_rules.len() > 0 {
+      let stored: Vec<String> = st.destinations.iter().filter_map(|d| d.stored.clone()).collect();
+      info!("Mail {} archived by rules {}", stored.join(", "), st.matched_rules.join(", "));
+    }
+
+    let md = clear_emaildata(st);
+    Self::send_line(tx, md, "250 2.0.0 Ok: queued".to_string(), Box::new(Self::wait_for_mail_from))
+  }
+
+  // Remember a header line; continuation lines are unfolded
+  fn add_header (mut md: EmailData, line: &String) -> EmailData {
+    if line.starts_with(' ') || line.starts_with('\t') {
+      if let Some(&mut (_, ref mut value)) = md.headers.last_mut() {
+        value.push(' ');
+        value.push_str(line.trim());
+      }
+    } else if let Some(i) = line.find(':') {
+      md.headers.push((line[..i].trim().to_lowercase(), line[i + 1..].trim().to_string()));
+    }
+    md
+  }
+
+  // Try the routing rules, in order, once the headers are known
+  fn apply_rules (mut md: EmailData) -> EmailData {
+    let sender = md.mail_from.as_ref().map(|a| a.normalized()).unwrap_or("".to_string());
+    let helo = md.client_helo.to_lowercase();
+    let rules = md.rules.clone();
+    for rule in rules.iter() {
+      if !Self::rule_matches(&md, rule, &sender, &helo) {
+        continue;
+      }
+      Self::add_rule_archivers(&mut md, rule);
+      if !rule.continue_matching {
+        break;
+      }
+    }
+    md
+  }
+
+  // Try the rules without header conditions, for a recipient no archiver
+  // is set up for; they can be told at RCPT time, and one matching accepts
+  // the recipient. Its archivers are added right away, as a rule matched
+  // earlier after the headers could end the search before it.
+  fn apply_envelope_rules (md: &mut EmailData) -> bool {
+    let sender = md.mail_from.as_ref().map(|a| a.normalized()).unwrap_or("".to_string());
+    let helo = md.client_helo.to_lowercase();
+    let rules = md.rules.clone();
+    let mut matched = false;
+    for rule in rules.iter().filter(|r| r.headers.len() == 0) {
+      if !Self::rule_matches(md, rule, &sender, &helo) {
+        continue;
+      }
+      Self::add_rule_archivers(md, rule);
+      matched = true;
+      if !rule.continue_matching {
+        break;
+      }
+    }
+    matched
+  }
+
+  fn add_rule_archivers (md: &mut EmailData, rule: &config::RuleSetup) {
+    if md.matched_rules.contains(&rule.name) {
+      return;
+    }
+    debug!("Rule {} matched, adding archivers {}", rule.name, rule.archivers.join(", "));
+    md.matched_rules.push(rule.name.clone());
+    for name in rule.archivers.iter() {
+      let archiver = md.archivers.iter().find(|a| a.name.as_ref() == Some(name)).cloned();
+      if let Some(a) = archiver {
+        let values = Self::envelope_variables(md, None);
+        let archive_path = template::expand(&a.archive_path, &values);
+        let file_name = template::expand(&a.file_name, &values);
+        Self::add_destination(md, archive_path, file_name, a.storage.clone(), a.compression.clone(), a.encryption_key.clone());
+        md.size_limit = match (md.size_limit, a.max_message_size) {
+          (Some(x), Some(y)) => Some(cmp::min(x, y)),
+          (x, y) => x.or(y),
+        };
+      }
+    }
+  }
+
+  fn add_destination (md: &mut EmailData, archive_path: String, file_name: String, storage: config::StorageType, compression: config::Compression,
+                    encryption_key: Option<Vec<u8>>) {
+    if !md.destinations.iter().any(|d| d.archive_path == archive_path && d.file_name == file_name) {
+      md.destinations.push(Destination { archive_path: archive_path, file_name: file_name, storage: storage, compression: compression,
+                                         encryption_key: encryption_key, delivery: None, stored: None });
+    }
+  }
+
+  // Template values known once the recipient is accepted; rules have no recipient
+  fn envelope_variables (md: &EmailData, recipient: Option<&Address>) -> Vec<(String, String)> {
+    let mut values = Vec::new();
+    if let Some(r) = recipient {
+      values.push(("recipient".to_string(), r.normalized()));
+      values.push(("recipient_local".to_string(), r.local_part.to_lowercase()));
+      values.push(("recipient_domain".to_string(), r.domain.clone()));
+    }
+    let (sender, sender_domain) = match md.mail_from {
+      Some(ref a) => (a.normalized(), a.domain.clone()),
+      None => ("".to_string(), "".to_string()),
+    };
+    values.push(("sender".to_string(), sender));
+    values.push(("sender_domain".to_string(), sender_domain));
+    values.push(("client_ip".to_string(), md.client_addr.map(|ip| ip.to_string()).unwrap_or("local".to_string())));
+    values.push(("helo".to_string(), md.client_helo.to_lowercase()));
+    values
+  }
+
+  // Template values known once the message is being spooled; without a
+  // usable Message-ID the uuid stands in for it
+  fn message_variables (md: &EmailData) -> Vec<(String, String)> {
+    let (id, safe) = match md.message_id {
+      Some((ref id, ref safe)) => (id.clone(), safe.clone()),
+      None => (md.uuid.clone(), md.uuid.clone()),
+    };
+    let mut hasher = Sha256::default();
+    hasher.input(id.as_bytes());
+    let hash: String = hasher.result().iter().map(|b| format!("{:02x}", b)).collect();
+    vec![("prefix".to_string(), md.prefix.clone()),
+         ("message_id".to_string(), safe),
+         ("message_id_hash".to_string(), hash),
+         ("uuid".to_string(), md.uuid.clone())]
+  }
+
+  fn rule_matches (md: &EmailData, rule: &config::RuleSetup, sender: &str, helo: &str) -> bool {
+    if let Some(ref p) = rule.sender {
+      if p.matches(sender).is_none() {
+        return false;
+      }
+    }
+    if rule.client.len() > 0 {
+      match md.client_addr {
+        Some(ref ip) if rule.client.iter().any(|c| c.contains(ip)) => {},
+        _ => return false,
+      }
+    }
+    if let Some(ref p) = rule.helo {
+      if p.matches(helo).is_none() {
+        return false;
+      }
+    }
+    for &(ref name, ref p) in rule.headers.iter() {
+      if !md.headers.iter().any(|&(ref n, ref v)| n == name && p.matches(&v.to_lowercase()).is_some()) {
+        return false;
+      }
+    }
+    true
+  }
+
+  fn drain_lines (mut md: EmailData) -> EmailData {
+    if !md.spooling {
+      return md;
+    }
+    for m in md.mail_data.drain(..) {
+      if md.spool_error.is_some() {
+        continue;
+      }
+      for d in md.destinations.iter_mut() {
+        if let Some(ref mut delivery) = d.delivery {
+          if let Err(e) = delivery.append(&m) {
+            md.spool_error = Some(e);
+            break;
           }
         }
-        st.mail_data.push(line);
+      }
+    }
+    md
+  }
 
-        // flush stored lines
-        if st.mail_data.len() > 64 {
-          st = Self::drain_lines(st);
+  // Begin a delivery in every destination, once it is known where the
+  // message goes; when that fails the rest of the message is read and
+  // thrown away
+  fn open_spool (mut md: EmailData) -> EmailData {
+    md.spooling = true;
+    if md.destinations.len() == 0 {
+      md.spool_error = Some(SpoolError::other("No archive for message"));
+      return md;
+    }
+    let envelopes: Result<Vec<Envelope>, SpoolError> = md.destinations.iter().map(|d| Self::envelope(&md, d)).collect();
+    let envelopes = match envelopes {
+      Ok(envelopes) => envelopes,
+      Err(e) => {
+        error!("Cannot spool mail: {}", e.message);
+        md.spool_error = Some(e);
+        return md;
+      }
+    };
+    for (d, envelope) in md.destinations.iter().zip(envelopes.iter()) {
+      Self::report_duplicate(&md, d, envelope);
+    }
+    let mut failed = None;
+    for (d, envelope) in md.destinations.iter_mut().zip(envelopes.iter()) {
+      match storage::open(&d.storage, &d.compression, &d.encryption_key).begin(envelope) {
+        Ok(delivery) => d.delivery = Some(delivery),
+        Err(e) => {
+          failed = Some(e);
+          break;
         }
-        Self::get_data(tx, st)
       }
-    }))
+    }
+    if let Some(e) = failed {
+      error!("Cannot spool mail: {}", e.message);
+      md = Self::discard_file(md);
+      md.spool_error = Some(e);
+      return md;
+    }
+    Self::drain_lines(md)
   }
 
-  fn drain_lines (mut md: EmailData) -> EmailData {
-    // easiest solution to "cannot move out of borrowed content" was to 'take'
-    // the value and put it back in...
-    match md.mail_file.take() {
-      None => {
-      },
-      Some(mut file) => {
-        for m in md.mail_data.drain(..) {
-          let _ = file.write_all(m.as_bytes());
-          let _ = file.write_all(b"\r\n");
+  // Commit the message in every destination; a message is archived
+  // everywhere or nowhere, so should one fail the others are undone
+  fn commit_all (md: &mut EmailData) -> Result<(), SpoolError> {
+    if let Some(ref e) = md.spool_error {
+      return Err(e.clone());
+    }
+    let mut result = Ok(());
+    for d in md.destinations.iter_mut() {
+      let delivery = match d.delivery.take() {
+        Some(delivery) => delivery,
+        None => {
+          // never begun, the message would silently be missing here
+          result = Err(SpoolError::other(&format!("No delivery begun in {}", d.archive_path)));
+          continue;
+        },
+      };
+      if result.is_err() {
+        delivery.abort();
+        continue;
+      }
+      match delivery.commit() {
+        Ok(location) => {
+          info!("Archived mail to {}", location);
+          d.stored = Some(location);
+        },
+        Err(e) => result = Err(e),
+      }
+    }
+    if result.is_err() {
+      for d in md.destinations.iter_mut() {
+        if let Some(location) = d.stored.take() {
+          storage::open(&d.storage, &d.compression, &d.encryption_key).remove(&location);
         }
-        md.mail_file = Some(file);
+      }
+    }
+    result
+  }
+
+  // Throw away what has been spooled of this message
+  fn discard_file (mut md: EmailData) -> EmailData {
+    md.mail_data.clear();
+    for d in md.destinations.iter_mut() {
+      if let Some(delivery) = d.delivery.take() {
+        delivery.abort();
       }
     }
     md
   }
 
+  // A template through strftime for a message received at datetime; the
+  // templates were checked with the configuration, so this should not fail
+  fn format_time (template: &String, datetime: &time::Tm) -> Result<String, SpoolError> {
+    time::strftime(template, datetime).map_err(|e| SpoolError::other(&format!("Cannot expand {}: {}", template, e)))
+  }
 
-  fn make_file (md: &EmailData, name: &String) -> File {
-    let tmpath = match time::strftime(&md.archive_path, &md.datetime) {
-      Ok(p) => p,
-      _ => { "/tmp".to_string() }
+  // Where a message goes in a destination: archive_path and file_name with
+  // the message variables filled in, through strftime
+  fn envelope (md: &EmailData, d: &Destination) -> Result<Envelope, SpoolError> {
+    let values = Self::message_variables(md);
+    let directory = try!(Self::format_time(&template::expand(&d.archive_path, &values), &md.datetime));
+    let file_name = try!(Self::format_time(&template::expand(&d.file_name, &values), &md.datetime));
+    Ok(Envelope {
+      directory: directory,
+      file_name: file_name,
+      sender: md.mail_from.as_ref().map(|a| a.normalized()).unwrap_or("".to_string()),
+      recipients: md.rcpt_to.iter().map(|a| a.normalized()).collect(),
+      message_id: md.message_id.as_ref().map(|&(ref id, _)| id.clone()).unwrap_or("".to_string()),
+      received: md.datetime,
+    })
+  }
+
+  // The {prefix} makes names unique per connection, so a message with a
+  // Message-ID seen before would not collide with the earlier one; look
+  // for its name with any prefix instead
+  fn report_duplicate (md: &EmailData, d: &Destination, envelope: &Envelope) {
+    if md.message_id.is_none() || !d.file_name.contains("{prefix}") ||
+       !(d.file_name.contains("{message_id}") || d.file_name.contains("{message_id_hash}")) {
+      return;
+    }
+    let values: Vec<(String, String)> = Self::message_variables(md).into_iter().filter(|&(ref n, _)| n != "prefix").collect();
+    let name = match Self::format_time(&template::expand(&d.file_name, &values), &md.datetime) {
+      Ok(name) => name,
+      Err(_) => return,
     };
-    let filepath = format!("{}/{}-{}.eml", tmpath, md.prefix, name);
-    info!("Spooling mail to {}", filepath.clone());
-    match File::create(filepath.clone()) {
-      Ok(file) => {
-        return file;
-      },
-      Err(_) => {
-        // perhaps dir is not created...
-        let _ = fs::create_dir_all(tmpath);
-        // now try again before failing.
-        return File::create(filepath).unwrap();
-      }
+    let i = name.find("{prefix}").unwrap();
+    if let Some(existing) = storage::find_similar(&envelope.directory, &name[..i], &name[i + "{prefix}".len()..]) {
+      warn!("Duplicate Message-ID <{}>, stored before as {}", envelope.message_id, existing);
     }
   }
-     
+
   fn parse_messageid(line: &String) -> Option<(String, String)> {
     
     let myline = line.clone();
@@ -407,3 +1240,124 @@ impl SmtpProto {
     Some((trimmed, safe))
   }
 }
+
+// A line of message data without its line terminator
+fn line_content(line: &[u8]) -> &[u8] {
+  let mut end = line.len();
+  if end > 0 && line[end - 1] == b'\n' {
+    end = end - 1;
+  }
+  if end > 0 && line[end - 1] == b'\r' {
+    end = end - 1;
+  }
+  &line[..end]
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio_core::io::{Codec, EasyBuf};
+  use super::{make_emaildata, Frame, SmtpCodec, SmtpProto, MAX_DATA_PIECE};
+
+  // A codec that has just sent 354
+  fn data_codec() -> SmtpCodec {
+    let mut codec = SmtpCodec::new();
+    let mut out = Vec::new();
+    codec.encode("354 End data with <CR><LF>.<CR><LF>".to_string(), &mut out).unwrap();
+    codec
+  }
+
+  // Decode the data frames in buf, up to and including the end of data,
+  // which shows as None
+  fn decode_all(codec: &mut SmtpCodec, buf: &mut EasyBuf) -> Vec<Option<Vec<u8>>> {
+    let mut frames = Vec::new();
+    while let Some(frame) = codec.decode(buf).unwrap() {
+      match frame {
+        Frame::Data(bytes) => frames.push(Some(bytes)),
+        Frame::DataEnd => {
+          frames.push(None);
+          break;
+        },
+        _ => panic!("unexpected frame"),
+      }
+    }
+    frames
+  }
+
+  #[test]
+  fn bare_lf_and_cr_are_data() {
+    let mut codec = data_codec();
+    let mut buf = EasyBuf::from(b"a\nb\rc\r\n.\r\n".to_vec());
+    assert_eq!(decode_all(&mut codec, &mut buf), vec![Some(b"a\nb\rc\r\n".to_vec()), None]);
+  }
+
+  #[test]
+  fn bare_lf_dot_does_not_end_data() {
+    let mut codec = data_codec();
+    let mut buf = EasyBuf::from(b"a\n.\nb\r\n.\r\n".to_vec());
+    assert_eq!(decode_all(&mut codec, &mut buf), vec![Some(b"a\n.\nb\r\n".to_vec()), None]);
+  }
+
+  #[test]
+  fn leading_dot_is_unstuffed() {
+    let mut codec = data_codec();
+    let mut buf = EasyBuf::from(b"..x\r\n...\r\n.\r\n".to_vec());
+    assert_eq!(decode_all(&mut codec, &mut buf), vec![Some(b".x\r\n".to_vec()), Some(b"..\r\n".to_vec()), None]);
+  }
+
+  #[test]
+  fn dot_inside_line_is_kept() {
+    let mut codec = data_codec();
+    let mut buf = EasyBuf::from(b"a.b\r\nc..\r\n.\r\n".to_vec());
+    assert_eq!(decode_all(&mut codec, &mut buf), vec![Some(b"a.b\r\n".to_vec()), Some(b"c..\r\n".to_vec()), None]);
+  }
+
+  #[test]
+  fn data_ends_at_crlf_dot_crlf() {
+    let mut codec = data_codec();
+    let mut buf = EasyBuf::from(b"x\r\n.\r\nQUIT\r\n".to_vec());
+    assert_eq!(decode_all(&mut codec, &mut buf), vec![Some(b"x\r\n".to_vec()), None]);
+    // back in command mode
+    match codec.decode(&mut buf).unwrap() {
+      Some(Frame::Command(line)) => assert_eq!(line, "QUIT"),
+      _ => panic!("expected a command"),
+    }
+  }
+
+  #[test]
+  fn cr_is_held_back_at_piece_boundary() {
+    let mut codec = data_codec();
+    let mut data = vec![b'a'; MAX_DATA_PIECE];
+    data.push(b'\r');
+    let mut buf = EasyBuf::from(data);
+    assert_eq!(decode_all(&mut codec, &mut buf), vec![Some(vec![b'a'; MAX_DATA_PIECE])]);
+    assert_eq!(buf.as_slice(), b"\r");
+    buf.get_mut().extend_from_slice(b"\n.\r\n");
+    // the CRLF completes the long line, and the "." after it ends the data
+    assert_eq!(decode_all(&mut codec, &mut buf), vec![Some(b"\r\n".to_vec()), None]);
+  }
+
+  #[test]
+  fn crlf_ending_a_long_header_is_not_a_blank_line() {
+    let mut st = make_emaildata("test".to_string(), Vec::new(), None);
+    let mut header = b"X-Long: ".to_vec();
+    header.extend(vec![b'a'; MAX_DATA_PIECE]);
+    st = SmtpProto::push_line(st, header);
+    st = SmtpProto::push_line(st, b"\r\n".to_vec());
+    assert!(st.in_headers);
+    st = SmtpProto::push_line(st, b"Subject: after\r\n".to_vec());
+    assert!(st.headers.iter().any(|&(ref name, _)| name == "subject"));
+    st = SmtpProto::push_line(st, b"\r\n".to_vec());
+    assert!(!st.in_headers);
+  }
+
+  #[test]
+  fn lf_ending_a_long_chunk_line_is_not_a_blank_line() {
+    let mut st = make_emaildata("test".to_string(), Vec::new(), None);
+    st.chunking = true;
+    st = SmtpProto::push_line(st, vec![b'a'; MAX_DATA_PIECE + 1]);
+    st = SmtpProto::push_line(st, b"\n".to_vec());
+    assert!(st.in_headers);
+    st = SmtpProto::push_line(st, b"\n".to_vec());
+    assert!(!st.in_headers);
+  }
+}
@@ -0,0 +1,80 @@
+//
+// SMTP commands, RFC 5321 section 4.1. Verbs are case insensitive; a
+// command with a known verb but bad arguments is Invalid, answered by 501,
+// an unknown verb is answered by 500.
+pub enum Command {
+  Helo(String),
+  Ehlo(String),
+  MailFrom(String),   // everything after "MAIL FROM:"
+  RcptTo(String),     // everything after "RCPT TO:"
+  Data,
+  Bdat(u64, bool),    // chunk size, and whether it is the last one
+  Rset,
+  Noop,
+  Vrfy(String),
+  Help,
+  Quit,
+  StartTls,
+  Invalid(String),    // the reason, for the 501 reply
+  Unknown(String),    // the verb
+}
+
+pub fn parse(line: &str) -> Command {
+  let (verb, arg) = match line.find(' ') {
+    Some(i) => (line[..i].to_uppercase(), line[i + 1..].trim()),
+    None => (line.to_uppercase(), ""),
+  };
+  match verb.as_str() {
+    "HELO" | "EHLO" if arg.len() == 0 => Command::Invalid(format!("Syntax: {} hostname", verb)),
+    "HELO" => Command::Helo(arg.to_string()),
+    "EHLO" => Command::Ehlo(arg.to_string()),
+    "MAIL" => match strip_prefix(arg, "FROM:") {
+      Some(path) => Command::MailFrom(path.to_string()),
+      None => Command::Invalid("Syntax: MAIL FROM:<address>".to_string()),
+    },
+    "RCPT" => match strip_prefix(arg, "TO:") {
+      Some(path) => Command::RcptTo(path.to_string()),
+      None => Command::Invalid("Syntax: RCPT TO:<address>".to_string()),
+    },
+    "BDAT" => match parse_bdat(line) {
+      Some((size, last)) => Command::Bdat(size, last),
+      None => Command::Invalid("Syntax: BDAT size [LAST]".to_string()),
+    },
+    "VRFY" if arg.len() == 0 => Command::Invalid("Syntax: VRFY address".to_string()),
+    "VRFY" => Command::Vrfy(arg.to_string()),
+    "NOOP" => Command::Noop,
+    "HELP" => Command::Help,
+    "DATA" | "RSET" | "QUIT" | "STARTTLS" if arg.len() > 0 => Command::Invalid(format!("Syntax: {}", verb)),
+    "DATA" => Command::Data,
+    "RSET" => Command::Rset,
+    "QUIT" => Command::Quit,
+    "STARTTLS" => Command::StartTls,
+    _ => Command::Unknown(verb),
+  }
+}
+
+// "BDAT 1000" or "BDAT 1000 LAST", gives the chunk size and last flag
+pub fn parse_bdat(line: &str) -> Option<(u64, bool)> {
+  let words: Vec<&str> = line.split(' ').collect();
+  if words.len() < 2 || words.len() > 3 || words[0].to_uppercase() != "BDAT" {
+    return None;
+  }
+  let size = match words[1].parse::<u64>() {
+    Ok(size) => size,
+    Err(_) => return None,
+  };
+  match words.get(2) {
+    None => Some((size, false)),
+    Some(w) if w.to_uppercase() == "LAST" => Some((size, true)),
+    Some(_) => None,
+  }
+}
+
+// Case insensitive prefix match, "from:" is as good as "FROM:"
+fn strip_prefix<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+  if s.len() >= prefix.len() && s.is_char_boundary(prefix.len()) && s[..prefix.len()].eq_ignore_ascii_case(prefix) {
+    Some(&s[prefix.len()..])
+  } else {
+    None
+  }
+}
@@ -1,30 +1,317 @@
 
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
-use yaml_rust::YamlLoader;
+use yaml_rust::{Yaml, YamlLoader};
+use libc;
+
+use address::Address;
+use pattern::{self, Cidr, RecipientPattern};
+use storage;
+use template;
+use capability::{self, Capability};
 
 pub struct Config {
-  pub listen: String,
+  pub listen: Vec<ListenerSetup>,
   pub servername: String,
   pub user: Option<String>,
   pub group: Option<String>,
   pub log_level: String,
+  pub extensions: Vec<Capability>,
+  pub tls_identity: Option<TlsIdentity>,
+  pub tls_identity_password: String,
+  pub require_tls: bool,
+  pub max_message_size: Option<u64>,
+  pub unknown_recipients: UnknownRecipients,
   pub archivers: Vec<ArchiverSetup>,
+  pub rules: Vec<RuleSetup>,
+}
+
+// Where the TLS certificate, chain and private key are read from
+#[derive(Clone, PartialEq)]
+pub enum TlsIdentity {
+  Pkcs12(String),         // tls_identity, with tls_identity_password
+  Pem(String, String),    // tls_certificate, with the chain after it, and tls_key
+}
+
+impl fmt::Display for TlsIdentity {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      TlsIdentity::Pkcs12(ref path) => write!(f, "{}", path),
+      TlsIdentity::Pem(ref certificate, ref key) => write!(f, "{} and {}", certificate, key),
+    }
+  }
+}
+
+// What to do with a recipient no archiver is set up for
+#[derive(Clone)]
+pub enum UnknownRecipients {
+  Reject,             // 550
+  Defer,              // 450, e.g. while the configuration is being changed
+  Catchall(String),   // archive in this archive_path
+}
+
+#[derive(Clone, PartialEq)]
+pub enum TlsMode {
+  None,       // plain text only
+  StartTls,   // plain text, STARTTLS offered
+  Implicit,   // TLS from the first byte, SMTPS
+}
+
+#[derive(Clone)]
+pub enum Endpoint {
+  Tcp(String),             // "192.0.2.25:25" or "[2001:db8::25]:25"
+  Unix(String, Option<u32>),  // socket path and permissions
+}
+
+impl fmt::Display for Endpoint {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Endpoint::Tcp(ref address) => write!(f, "{}", address),
+      Endpoint::Unix(ref path, _) => write!(f, "unix:{}", path),
+    }
+  }
+}
+
+pub struct ListenerSetup {
+  pub endpoint: Endpoint,
+  pub tls: TlsMode,
+}
+
+impl Clone for ListenerSetup {
+  fn clone (&self) -> ListenerSetup {
+    ListenerSetup { endpoint: self.endpoint.clone(), tls: self.tls.clone() }
+  }
+}
+
+// The storage backend of an archiver, see storage
+#[derive(Clone, PartialEq)]
+pub enum StorageType {
+  Eml,        // a file per message
+  Maildir,    // archive_path is a Maildir
+  Mbox,       // messages appended to file_name, mboxrd
+  Dedup(String),  // messages stored once in the blob_path, by content
+}
+
+// How an archiver compresses messages, with the level
+#[derive(Clone, PartialEq)]
+pub enum Compression {
+  None,
+  Gzip(u32),    // 0 to 9
+  Zstd(i32),    // 1 to 22
 }
 
 pub struct ArchiverSetup {
-  pub recipient: String,
+  pub name: Option<String>,  // for rules to refer to
+  pub recipient: Option<RecipientPattern>,  // exact addresses are normalized, see Address::normalized
   pub archive_path: String,
+  pub file_name: String,
+  pub storage: StorageType,
+  pub compression: Compression,
+  pub encryption_key: Option<Vec<u8>>,    // PEM of the RSA public key
+  pub max_message_size: Option<u64>,
 }
 
 impl Clone for ArchiverSetup {
   fn clone (&self) -> ArchiverSetup {
-    ArchiverSetup { recipient: self.recipient.clone(), archive_path: self.archive_path.clone() }
+    ArchiverSetup { name: self.name.clone(), recipient: self.recipient.clone(), archive_path: self.archive_path.clone(),
+                    file_name: self.file_name.clone(), storage: self.storage.clone(), compression: self.compression.clone(),
+                    encryption_key: self.encryption_key.clone(), max_message_size: self.max_message_size }
+  }
+}
+
+// A routing rule; when all its conditions hold, once the headers have been
+// read, the message is archived in the named archivers as well. Rules are
+// tried in order, and unless `continue` is set the first match ends it.
+// A rule without header conditions can also accept a recipient that has
+// no archiver, at RCPT time.
+pub struct RuleSetup {
+  pub name: String,
+  pub sender: Option<RecipientPattern>,   // "<>" is the null sender
+  pub client: Vec<Cidr>,                  // any of these networks
+  pub helo: Option<RecipientPattern>,
+  pub headers: Vec<(String, RecipientPattern)>,   // lower case header name, value
+  pub archivers: Vec<String>,
+  pub continue_matching: bool,
+}
+
+impl Clone for RuleSetup {
+  fn clone (&self) -> RuleSetup {
+    RuleSetup { name: self.name.clone(), sender: self.sender.clone(), client: self.client.clone(), helo: self.helo.clone(),
+                headers: self.headers.clone(), archivers: self.archivers.clone(), continue_matching: self.continue_matching }
+  }
+}
+
+pub fn libc_gethostname() -> String {
+  let mut buf = [0u8; 256];
+  let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+  if rc != 0 {
+    return "localhost".to_string();
+  }
+  let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+  String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+// A size is a number of octets, or a string with a K, M or G suffix
+fn read_size(yaml: &Yaml, name: &str) -> Result<Option<u64>, String> {
+  match *yaml {
+    Yaml::BadValue | Yaml::Null => Ok(None),
+    Yaml::Integer(n) if n >= 0 => Ok(Some(n as u64)),
+    Yaml::String(ref s) => {
+      let (digits, factor) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1024),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (&s[..], 1),
+      };
+      match digits.trim().parse::<u64>() {
+        Ok(n) => Ok(Some(n * factor)),
+        Err(_) => Err(format!("invalid size {} in '{}'", s, name)),
+      }
+    },
+    _ => Err(format!("invalid size in '{}'", name)),
+  }
+}
+
+// compression is gzip, zstd or none, compression_level defaults to 6 for
+// gzip and 3 for zstd
+fn read_compression(yaml: &Yaml, i: i32) -> Result<Compression, String> {
+  let level = match yaml["compression_level"] {
+    Yaml::BadValue | Yaml::Null => None,
+    Yaml::Integer(n) => Some(n),
+    _ => return Err(format!("invalid compression_level in 'archivers[{}]'", i)),
+  };
+  match (yaml["compression"].as_str(), level) {
+    (None, None) | (Some("none"), None) => Ok(Compression::None),
+    (Some("gzip"), None) => Ok(Compression::Gzip(6)),
+    (Some("gzip"), Some(n)) if n >= 0 && n <= 9 => Ok(Compression::Gzip(n as u32)),
+    (Some("zstd"), None) => Ok(Compression::Zstd(3)),
+    (Some("zstd"), Some(n)) if n >= 1 && n <= 22 => Ok(Compression::Zstd(n as i32)),
+    (Some("gzip"), Some(n)) | (Some("zstd"), Some(n)) => Err(format!("compression_level {} out of range in 'archivers[{}]'", n, i)),
+    (None, Some(_)) | (Some("none"), Some(_)) => Err(format!("compression_level without compression in 'archivers[{}]'", i)),
+    (Some(other), _) => Err(format!("compression must be one of gzip, zstd or none, found {} in 'archivers[{}]'", other, i)),
   }
 }
 
-fn libc_gethostname() -> String {
-  "gethostname_to_be_implemented".to_string()
+// encryption_key names the PEM file of the RSA public key messages are
+// encrypted to; it is read along with the configuration
+fn read_public_key(yaml: &Yaml, i: i32) -> Result<Option<Vec<u8>>, String> {
+  let path = match yaml["encryption_key"].as_str() {
+    Some(path) => path,
+    None => return Ok(None),
+  };
+  let mut pem = Vec::new();
+  if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut pem)) {
+    return Err(format!("Cannot read encryption_key {} in 'archivers[{}]': {}", path, i, e));
+  }
+  try!(storage::check_public_key(&pem).map_err(|e| format!("invalid encryption_key {} in 'archivers[{}]': {}", path, i, e)));
+  Ok(Some(pem))
+}
+
+// A listener is either just an address, "unix:/path" for a local socket,
+// or a map with an address or path, and a tls mode
+fn read_listener(yaml: &Yaml, default_tls: &TlsMode, i: usize) -> Result<ListenerSetup, String> {
+  if let Some(address) = yaml.as_str() {
+    let endpoint = if address.starts_with("unix:") {
+      Endpoint::Unix(address[5..].to_string(), None)
+    } else {
+      Endpoint::Tcp(address.to_string())
+    };
+    return Ok(ListenerSetup { endpoint: endpoint, tls: default_tls.clone() });
+  }
+  let endpoint = match (yaml["address"].as_str(), yaml["path"].as_str()) {
+    (Some(address), None) => Endpoint::Tcp(address.to_string()),
+    (None, Some(path)) => {
+      // permissions as an octal string, e.g. "0660", so the MTA may connect
+      let mode = match yaml["mode"].as_str() {
+        None => None,
+        Some(m) => match u32::from_str_radix(m, 8) {
+          Ok(mode) => Some(mode),
+          Err(_) => return Err(format!("mode must be octal, found {} in 'listen[{}]'", m, i)),
+        },
+      };
+      Endpoint::Unix(path.to_string(), mode)
+    },
+    (Some(_), Some(_)) => return Err(format!("both address and path in 'listen[{}]'", i)),
+    (None, None) => return Err(format!("no address or path in 'listen[{}]'", i)),
+  };
+  let tls = match yaml["tls"].as_str() {
+    None => default_tls.clone(),
+    Some("none") => TlsMode::None,
+    Some("starttls") => TlsMode::StartTls,
+    Some("implicit") => TlsMode::Implicit,
+    Some(other) => return Err(format!("tls must be one of implicit, starttls or none, found {} in 'listen[{}]'", other, i)),
+  };
+  Ok(ListenerSetup { endpoint: endpoint, tls: tls })
+}
+
+// Patterns in rules compare without regard to case, as recipients do
+fn read_pattern(pattern: &str, name: &str) -> Result<RecipientPattern, String> {
+  match RecipientPattern::parse(pattern) {
+    Ok(RecipientPattern::Exact(s)) => Ok(RecipientPattern::Exact(s.to_lowercase())),
+    Ok(p) => Ok(p),
+    Err(e) => Err(format!("{} in '{}'", e, name)),
+  }
+}
+
+fn read_rule(yaml: &Yaml, archivers: &[ArchiverSetup], i: usize) -> Result<RuleSetup, String> {
+  let name = match yaml["name"].as_str() {
+    Some(n) => n.to_string(),
+    None => format!("rules[{}]", i),
+  };
+  let sender = match yaml["sender"].as_str() {
+    None => None,
+    Some("<>") => Some(RecipientPattern::Exact("".to_string())),
+    Some(s) => Some(try!(read_pattern(s, &format!("rules[{}].sender", i)))),
+  };
+  let mut client = Vec::new();
+  let networks = match yaml["client"] {
+    Yaml::String(_) => vec![yaml["client"].clone()],
+    Yaml::Array(ref list) => list.clone(),
+    _ => Vec::new(),
+  };
+  for network in networks.iter() {
+    match network.as_str().map(Cidr::parse) {
+      Some(Ok(cidr)) => client.push(cidr),
+      Some(Err(e)) => return Err(format!("{} in 'rules[{}].client'", e, i)),
+      None => return Err(format!("invalid network in 'rules[{}].client'", i)),
+    }
+  }
+  let helo = match yaml["helo"].as_str() {
+    None => None,
+    Some(h) => Some(try!(read_pattern(h, &format!("rules[{}].helo", i)))),
+  };
+  let mut headers = Vec::new();
+  if let Some(map) = yaml["headers"].as_hash() {
+    for (header, value) in map.iter() {
+      match (header.as_str(), value.as_str()) {
+        (Some(h), Some(v)) => headers.push((h.to_lowercase(), try!(read_pattern(v, &format!("rules[{}].headers.{}", i, h))))),
+        _ => return Err(format!("malformed entries in 'rules[{}].headers'", i)),
+      }
+    }
+  }
+  let names: Vec<String> = match yaml["archivers"] {
+    Yaml::String(ref s) => vec![s.clone()],
+    Yaml::Array(ref list) => list.iter().filter_map(|y| y.as_str().map(|s| s.to_string())).collect(),
+    _ => return Err(format!("no archivers in 'rules[{}]'", i)),
+  };
+  for n in names.iter() {
+    match archivers.iter().find(|a| a.name.as_ref() == Some(n)) {
+      None => return Err(format!("unknown archiver {} in 'rules[{}]'", n, i)),
+      Some(a) => {
+        // a rule has no recipient to take these from
+        let mut used = template::variables(&a.archive_path);
+        used.extend(template::variables(&a.file_name));
+        if used.iter().any(|v| v.starts_with("recipient"))
+            || pattern::path_variables(&a.archive_path).len() > 0 || pattern::path_variables(&a.file_name).len() > 0 {
+          return Err(format!("archiver {} in 'rules[{}]' uses recipient variables in its archive_path or file_name", n, i));
+        }
+      },
+    }
+  }
+  let continue_matching = yaml["continue"].as_bool().unwrap_or(false);
+  Ok(RuleSetup { name: name, sender: sender, client: client, helo: helo, headers: headers, archivers: names,
+                 continue_matching: continue_matching })
 }
 
 pub fn read_config(config_file: &String) -> Result<Config, String> {
@@ -39,13 +326,6 @@ pub fn read_config(config_file: &String) -> Result<Config, String> {
   let yaml = YamlLoader::load_from_str(&content).unwrap();
   let doc = &yaml[0];
 
-  let config_listen = match doc["listen"].as_str() {
-    Some(value) => value.to_string(),
-    None => {
-      return Err("Required configuration parameter 'listen' not found".to_string());
-    }
-  };
-
   let config_servername = match doc["servername"].as_str() {
     Some(value) => value.to_string(),
     None => libc_gethostname(),
@@ -67,25 +347,182 @@ pub fn read_config(config_file: &String) -> Result<Config, String> {
     Some(str) => Some(str.to_string()),
   };
  
+  let config_tls_identity = match (doc["tls_identity"].as_str(), doc["tls_certificate"].as_str(), doc["tls_key"].as_str()) {
+    (None, None, None) => None,
+    (Some(identity), None, None) => Some(TlsIdentity::Pkcs12(identity.to_string())),
+    (None, Some(certificate), Some(key)) => Some(TlsIdentity::Pem(certificate.to_string(), key.to_string())),
+    (Some(_), _, _) => return Err("give either 'tls_identity' or 'tls_certificate' and 'tls_key', not both".to_string()),
+    (None, _, _) => return Err("'tls_certificate' and 'tls_key' go together".to_string()),
+  };
+
+  let config_tls_identity_password = match doc["tls_identity_password"].as_str() {
+    Some(value) => value.to_string(),
+    None => "".to_string(),
+  };
+
+  let config_require_tls = match doc["require_tls"].as_bool() {
+    Some(value) => value,
+    None => false,
+  };
+
+  if config_require_tls && config_tls_identity.is_none() {
+    return Err("'require_tls' is set, but no TLS identity is configured".to_string());
+  }
+
+  // listeners offer STARTTLS unless told otherwise, if there is an identity
+  let default_tls = match config_tls_identity {
+    Some(_) => TlsMode::StartTls,
+    None => TlsMode::None,
+  };
+
+  let mut config_listen: Vec<ListenerSetup> = Vec::new();
+  match doc["listen"] {
+    Yaml::String(_) => config_listen.push(try!(read_listener(&doc["listen"], &default_tls, 0))),
+    Yaml::Array(ref list) => {
+      for (i, yaml) in list.iter().enumerate() {
+        config_listen.push(try!(read_listener(yaml, &default_tls, i)));
+      }
+    },
+    _ => {
+      return Err("Required configuration parameter 'listen' not found".to_string());
+    }
+  };
+
+  for l in config_listen.iter() {
+    if l.tls != TlsMode::None && config_tls_identity.is_none() {
+      return Err(format!("listener {} needs TLS, but no TLS identity is configured", l.endpoint));
+    }
+    if l.tls == TlsMode::None && config_require_tls {
+      return Err(format!("'require_tls' is set, but listener {} has 'tls: none'", l.endpoint));
+    }
+  }
+
+  let config_max_message_size = try!(read_size(&doc["max_message_size"], "max_message_size"));
+
+  let config_extensions = match doc["extensions"].as_vec() {
+    None => capability::default_capabilities(),
+    Some(list) => {
+      let mut extensions = Vec::new();
+      for (i, yaml) in list.iter().enumerate() {
+        match yaml.as_str().and_then(Capability::from_name) {
+          Some(c) => extensions.push(c),
+          None => return Err(format!("unknown extension {:?} in 'extensions[{}]'", yaml, i)),
+        }
+      }
+      extensions
+    }
+  };
+
+  if config_extensions.contains(&Capability::BinaryMime) && !config_extensions.contains(&Capability::Chunking) {
+    return Err("extension BINARYMIME requires CHUNKING in 'extensions'".to_string());
+  }
+
+  let config_unknown_recipients = match (doc["unknown_recipients"].as_str(), doc["catchall_path"].as_str()) {
+    (None, _) | (Some("reject"), _) => UnknownRecipients::Reject,
+    (Some("defer"), _) => UnknownRecipients::Defer,
+    (Some("catchall"), Some(path)) => {
+      try!(template::check(path).map_err(|e| format!("{} in 'catchall_path'", e)));
+      UnknownRecipients::Catchall(path.to_string())
+    },
+    (Some("catchall"), None) => return Err("'unknown_recipients' is catchall, but no 'catchall_path' is configured".to_string()),
+    (Some(other), _) => return Err(format!("unknown_recipients must be one of reject, defer or catchall, found {}", other)),
+  };
+
   let mut config_archivers: Vec<ArchiverSetup> = Vec::new();
   let mut i:i32 = 0;
   for yaml in doc["archivers"].as_vec().unwrap() {
+    let name = yaml["name"].as_str().map(|n| n.to_string());
+    let storage = match yaml["storage"].as_str() {
+      None | Some("eml") => StorageType::Eml,
+      Some("maildir") => StorageType::Maildir,
+      Some("mbox") => StorageType::Mbox,
+      Some("dedup") => match yaml["blob_path"].as_str() {
+        Some(p) => StorageType::Dedup(p.to_string()),
+        None => return Err(format!("dedup storage needs a blob_path, in 'archivers[{}]'", i)),
+      },
+      Some(other) => return Err(format!("unknown storage {} in 'archivers[{}]'", other, i)),
+    };
+    let compression = try!(read_compression(yaml, i));
+    // mbox and Maildir readers would not know the messages are compressed
+    if (storage == StorageType::Mbox || storage == StorageType::Maildir) && compression != Compression::None {
+      return Err(format!("mbox and maildir storage cannot be compressed, in 'archivers[{}]'", i));
+    }
+    let encryption_key = try!(read_public_key(yaml, i));
+    if (storage == StorageType::Mbox || storage == StorageType::Maildir) && encryption_key.is_some() {
+      return Err(format!("mbox and maildir storage cannot be encrypted, in 'archivers[{}]'", i));
+    }
+    if let StorageType::Dedup(_) = storage {
+      // identical messages must give identical blobs
+      if compression != Compression::None || encryption_key.is_some() {
+        return Err(format!("dedup storage cannot be compressed or encrypted, in 'archivers[{}]'", i));
+      }
+    }
+    let default_file_name = match storage {
+      StorageType::Mbox => template::DEFAULT_MBOX_NAME,
+      StorageType::Dedup(_) => template::DEFAULT_META_NAME,
+      _ => template::DEFAULT_FILE_NAME,
+    };
+    let file_name = yaml["file_name"].as_str().unwrap_or(default_file_name).to_string();
+    try!(template::check(&file_name).map_err(|e| format!("{} in 'archivers[{}].file_name'", e, i)));
+    if let Some(a) = yaml["archive_path"].as_str() {
+      try!(template::check(a).map_err(|e| format!("{} in 'archivers[{}].archive_path'", e, i)));
+    }
     match (yaml["recipient"].as_str(), yaml["archive_path"].as_str()) {
-          (Some(r), Some(a)) => config_archivers.push(ArchiverSetup{ recipient: r.to_string(), archive_path: a.to_string() }),
+          (Some(r), Some(a)) => {
+            let recipient = match RecipientPattern::parse(r) {
+              Ok(RecipientPattern::Exact(_)) => match Address::parse(r) {
+                Ok(address) => RecipientPattern::Exact(address.normalized()),
+                Err(e) => return Err(format!("invalid recipient {} in 'archivers[{}]': {}", r, i, e)),
+              },
+              Ok(p) => p,
+              Err(e) => return Err(format!("invalid recipient {} in 'archivers[{}]': {}", r, i, e)),
+            };
+            let variables = recipient.variables();
+            for v in pattern::path_variables(a).into_iter().chain(pattern::path_variables(&file_name)) {
+              if !variables.contains(&v) {
+                return Err(format!("archive_path or file_name uses %{{{}}}, which recipient {} does not capture, in 'archivers[{}]'", v, r, i));
+              }
+            }
+            let max_message_size = try!(read_size(&yaml["max_message_size"], &format!("archivers[{}].max_message_size", i)));
+            config_archivers.push(ArchiverSetup{ name: name, recipient: Some(recipient), archive_path: a.to_string(), file_name: file_name,
+                                                 storage: storage.clone(), compression: compression.clone(),
+                                                 encryption_key: encryption_key.clone(), max_message_size: max_message_size })
+          },
+          (None, Some(a)) if name.is_some() => {
+            // only used by rules
+            let max_message_size = try!(read_size(&yaml["max_message_size"], &format!("archivers[{}].max_message_size", i)));
+            config_archivers.push(ArchiverSetup{ name: name, recipient: None, archive_path: a.to_string(), file_name: file_name,
+                                                 storage: storage.clone(), compression: compression.clone(),
+                                                 encryption_key: encryption_key.clone(), max_message_size: max_message_size })
+          },
           (Some(r), None) => return Err(format!("found recipient {}, but no archive path, in 'archivers[{}]'", r, i)),
-          (None, Some(a)) => return Err(format!("found archive_path {}, but no recipient, in 'archivers[{}]'", a, i)),
+          (None, Some(a)) => return Err(format!("found archive_path {}, but no recipient or name, in 'archivers[{}]'", a, i)),
           (None, None) => return Err(format!("malformed entries in 'archivers[{}]'", i)),
         }
     i = i + 1;
   };
 
+  let mut config_rules: Vec<RuleSetup> = Vec::new();
+  if let Some(list) = doc["rules"].as_vec() {
+    for (i, yaml) in list.iter().enumerate() {
+      config_rules.push(try!(read_rule(yaml, &config_archivers, i)));
+    }
+  }
+
   Ok(Config {
     listen: config_listen,
     servername: config_servername,
     log_level: config_log_level,
     user: config_user,
     group: config_group,
+    extensions: config_extensions,
+    tls_identity: config_tls_identity,
+    tls_identity_password: config_tls_identity_password,
+    require_tls: config_require_tls,
+    max_message_size: config_max_message_size,
+    unknown_recipients: config_unknown_recipients,
     archivers: config_archivers,
+    rules: config_rules,
   })
 }
 
@@ -10,6 +10,15 @@ extern crate uuid;
 extern crate getopts;
 extern crate yaml_rust;
 extern crate libc;
+extern crate net2;
+extern crate native_tls;
+extern crate tokio_tls;
+extern crate tokio_uds;
+extern crate regex;
+extern crate sha2;
+extern crate flate2;
+extern crate zstd;
+extern crate openssl;
 
 #[macro_use]
 extern crate log;
@@ -18,18 +27,33 @@ extern crate badlog;
 use futures::Future;
 use futures::stream::Stream;
 use tokio_core::reactor::{Core, Handle};
-use tokio_core::net::TcpListener;
-use tokio_core::io::{IoStream, IoFuture};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::io::{Io, IoStream, IoFuture};
+use tokio_uds::{UnixListener, UnixStream};
 use tokio_signal::unix;
 use getopts::Options;
 use std::env;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
 use std::process;
+use std::rc::Rc;
 use std::ffi::CString;
 use libc::{getpid, setgid, setuid, getgrnam, getpwnam};
+use native_tls::TlsAcceptor;
+use net2::TcpBuilder;
 
 
+mod address;
+mod capability;
 mod codec;
+mod command;
 mod config;
+mod pattern;
+mod storage;
+mod template;
+mod tls;
 
 fn print_usage(opts: Options) {
   let brief = "Usage: mail-archiver --config [YAML-CONFIG]";
@@ -38,17 +62,123 @@ fn print_usage(opts: Options) {
 }
 
 
-enum Incoming<T> {
-  Connection(T),
+// Load the TLS identity, if one is configured
+fn tls_acceptor(config: &config::Config) -> Result<Option<Rc<TlsAcceptor>>, String> {
+  match config.tls_identity {
+    None => Ok(None),
+    Some(ref identity) => tls::load_acceptor(identity, &config.tls_identity_password).map(|a| Some(Rc::new(a))),
+  }
+}
+
+// Listeners are not bound again on reload, so those bound with TLS must
+// keep an identity
+fn check_reload(bound: &Vec<config::ListenerSetup>, new: &config::Config) -> Result<(), String> {
+  match bound.iter().find(|l| l.tls != config::TlsMode::None) {
+    Some(l) if new.tls_identity.is_none() => Err(format!("listener {} is bound with TLS, but no TLS identity is configured", l.endpoint)),
+    _ => Ok(()),
+  }
+}
+
+// On reload the identity files may no longer be readable, privileges are
+// dropped; as long as the configuration names the same identity, the one
+// loaded is kept
+fn reload_tls_acceptor(old: &config::Config, current: &Option<Rc<TlsAcceptor>>, new: &config::Config) -> Result<Option<Rc<TlsAcceptor>>, String> {
+  match tls_acceptor(new) {
+    Ok(acceptor) => Ok(acceptor),
+    Err(e) => {
+      if new.tls_identity == old.tls_identity && new.tls_identity_password == old.tls_identity_password {
+        warn!("{}, keeping the TLS identity loaded", e);
+        Ok(current.clone())
+      } else {
+        Err(e)
+      }
+    },
+  }
+}
+
+enum Incoming {
+  Connection(TcpStream, SocketAddr, config::TlsMode),
+  Local(UnixStream, config::TlsMode),
   Usr1,
 }
 
+// Bind a listener endpoint; the error names the endpoint
+fn bind_listener(l: &config::ListenerSetup, handle: &Handle) -> Result<Box<Stream<Item = Incoming, Error = io::Error>>, String> {
+  let tls = l.tls.clone();
+  match l.endpoint {
+    config::Endpoint::Tcp(ref address) => {
+      let addr: SocketAddr = match address.parse() {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Cannot listen on {}: {}", address, e)),
+      };
+      match tcp_listener(&addr, handle) {
+        Ok(socket) => Ok(Box::new(socket.incoming().map(move |(s, a)| Incoming::Connection(s, a, tls.clone())))),
+        Err(e) => Err(format!("Cannot listen on {}: {}", address, e)),
+      }
+    },
+    config::Endpoint::Unix(ref path, permissions) => {
+      // a socket left behind by a previous run would make bind fail
+      if let Ok(meta) = fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+          let _ = fs::remove_file(path);
+        }
+      }
+      let socket = match UnixListener::bind(path, handle) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Cannot listen on unix:{}: {}", path, e)),
+      };
+      if let Some(mode) = permissions {
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+          return Err(format!("Cannot set mode {:o} on unix:{}: {}", mode, path, e));
+        }
+      }
+      Ok(Box::new(socket.incoming().map(move |(s, _)| Incoming::Local(s, tls.clone()))))
+    },
+  }
+}
+
+// IPv6 sockets take IPv6 only, so [::]:25 can be listened on next to
+// 0.0.0.0:25; by default Linux has them take IPv4 too, and the second
+// bind fails
+fn tcp_listener(addr: &SocketAddr, handle: &Handle) -> io::Result<TcpListener> {
+  let builder = try!(if addr.is_ipv6() { TcpBuilder::new_v6() } else { TcpBuilder::new_v4() });
+  if addr.is_ipv6() {
+    try!(builder.only_v6(true));
+  }
+  try!(builder.reuse_address(true));
+  let listener = try!(builder.bind(addr).and_then(|b| b.listen(1024)));
+  TcpListener::from_listener(listener, addr, handle)
+}
+
+// An IPv4 client of an IPv6 socket shows up as ::ffff:a.b.c.d; rules on
+// IPv4 networks, and {client_ip}, want a.b.c.d
+fn client_ip(addr: &SocketAddr) -> IpAddr {
+  match addr.ip() {
+    IpAddr::V6(ip) => {
+      let s = ip.segments();
+      match ip.to_ipv4() {
+        Some(v4) if s[..5].iter().all(|&x| x == 0) && s[5] == 0xffff => IpAddr::V4(v4),
+        _ => IpAddr::V6(ip),
+      }
+    },
+    ip => ip,
+  }
+}
+
+fn spawn_connection<T: Io + 'static>(handle: &Handle, binder: &codec::SmtpProto, socket: T, md: codec::EmailData, tls: config::TlsMode) {
+  let fut = binder.bind_transport(socket, md, tls);
+  handle.spawn(fut.then(|_| Ok(())));
+}
+
 fn main() {
 
     // make options structure
     let mut opts = Options::new();
     opts.optopt("c", "config", "Yaml configuration file for mail-archiver", "FILE");
     opts.optflag("t", "template", "print out a template configuration file and exit");
+    opts.optopt("d", "decompress", "write an archived message to stdout, decompressed, and exit", "FILE");
+    opts.optopt("", "decrypt", "write an encrypted archived message to stdout, decrypted with --key, and exit", "FILE");
+    opts.optopt("k", "key", "PEM file with the private key for --decrypt", "FILE");
     opts.optflag("h", "help", "print this help");
     let args: Vec<String> = env::args().collect();
     let matches = match opts.parse(&args[1..]) {
@@ -63,16 +193,71 @@ fn main() {
     // should we just print a template yml file?
     if matches.opt_present("t") {
       println!("---
-listen: 192.168.1.77:25
+listen:
+    - 192.168.1.77:25
+    - address: 192.168.1.77:465
+      tls: implicit
+    - \"[2001:db8::77]:25\"
+    - path: /var/run/mail-archiver.sock
+      mode: \"0660\"
+      tls: none
 servername: server.domain.com
 user: mailarchive
 group: mailarchive
 log_level: DEBUG
+tls_identity: /etc/mail-archiver/identity.p12
+tls_identity_password: secret
+# or PEM files
+# tls_certificate: /etc/mail-archiver/cert.pem
+# tls_key: /etc/mail-archiver/key.pem
+require_tls: false
+max_message_size: 50M
+unknown_recipients: reject
+catchall_path: /mnt/storage/catchall/%Y/%m-%d
+extensions:
+    - ENHANCEDSTATUSCODES
+    - PIPELINING
+    - SIZE
+    - 8BITMIME
+    - CHUNKING
+    - BINARYMIME
+    - STARTTLS
 archivers:
     - recipient: archive@domain.com
       archive_path: /mnt/storage/archive/%Y/%m-%d/%H:00
+      storage: eml
     - recipient: smallarchive@domain.com
       archive_path: /mnt/storage/smallarchive/%Y/%m-%d
+      max_message_size: 10M
+    - recipient: '/^journal\\+(?P<dept>[a-z]+)@domain\\.com$/'
+      archive_path: /mnt/storage/journal/%{dept}/%Y
+    - recipient: '@otherdomain.com'
+      archive_path: /mnt/storage/otherdomain/{recipient_local}/%Y/%m-%d
+      file_name: "%H%M%S-{message_id_hash}.eml"
+      compression: zstd
+      compression_level: 9
+      encryption_key: /etc/mail-archiver/archive-public.pem
+    - name: journal
+      archive_path: /mnt/storage/journal/maildir
+      storage: maildir
+    - recipient: mailinglists@domain.com
+      archive_path: /mnt/storage/mailinglists/%Y
+      storage: mbox
+    - recipient: journal@domain.com
+      archive_path: /mnt/storage/journal-meta/%Y/%m-%d
+      storage: dedup
+      blob_path: /mnt/storage/blobs
+rules:
+    - name: exchange-journal
+      client: 192.168.1.0/24
+      headers:
+          X-MS-Journal-Report: '*'
+      archivers: journal
+    - name: from-boss
+      sender: boss@domain.com
+      archivers:
+          - journal
+      continue: true
 ");
        process::exit(0);
     }
@@ -82,6 +267,29 @@ archivers:
       process::exit(0);
     }
 
+    if let Some(file) = matches.opt_str("d") {
+      if let Err(e) = storage::decompress(&file) {
+        println!("Cannot decompress {}: {}", file, e);
+        process::exit(1);
+      }
+      process::exit(0);
+    }
+
+    if let Some(file) = matches.opt_str("decrypt") {
+      let key = match matches.opt_str("k") {
+        Some(k) => k,
+        None => {
+          println!("Supply -k/--key parameter with --decrypt");
+          process::exit(1);
+        },
+      };
+      if let Err(e) = storage::decrypt(&file, &key) {
+        println!("Cannot decrypt {}: {}", file, e);
+        process::exit(1);
+      }
+      process::exit(0);
+    }
+
     let config_file = {
       match matches.opt_str("c") {
         Some(s) => s,
@@ -100,15 +308,33 @@ archivers:
     badlog::init(Some(config.log_level.clone()));
     let pid = unsafe { getpid() };
     
+    let addresses: Vec<String> = config.listen.iter().map(|l| l.endpoint.to_string()).collect();
     info!("mail-archiver starting up, pid is {}, read config from {}, listening on {}, {} archiver setups configured, log level set to {}",
-           pid, config_file, config.listen, config.archivers.len(), config.log_level);
-    // Specify the localhost address
-    let addr = config.listen.parse().unwrap();
+           pid, config_file, addresses.join(", "), config.archivers.len(), config.log_level);
 
     // make the core
     let mut core = Core::new().unwrap();
     let handle = core.handle();
-    let socket = TcpListener::bind(&addr, &handle).unwrap();
+    let bound = config.listen.clone();
+    let mut sockets = Vec::new();
+    for l in config.listen.iter() {
+      match bind_listener(l, &handle) {
+        Ok(incoming) => sockets.push(incoming),
+        Err(e) => {
+          error!("{}", e);
+          process::exit(1);
+        }
+      }
+    }
+
+    // the identity is readable by root only, as a rule
+    let mut acceptor = match tls_acceptor(&config) {
+      Ok(acceptor) => acceptor,
+      Err(e) => {
+        error!("Cannot set up TLS: {}", e);
+        process::exit(1);
+      }
+    };
 
     // downgrade uid/gid
     if config.user.is_some() {
@@ -136,37 +362,61 @@ archivers:
     // make the stream
     let usr1_stream = core.run(usr1).unwrap();
 
-    let prg_prefix = time::strftime("%H%M%S", &time::now_utc()).unwrap();
+    // unique across restarts, so prefixes of earlier runs are not reused
+    let prg_prefix = format!("{}-{}", time::strftime("%Y%m%d%H%M%S", &time::now_utc()).unwrap(), pid);
     let mut connection_counter = 0u64;
     
 
     // combine all streams to one
-    let all = socket.incoming().map(|c| Incoming::Connection(c))
-             .select(usr1_stream.map(|_| Incoming::Usr1));
+    let mut all: Box<Stream<Item = Incoming, Error = io::Error>> =
+      Box::new(usr1_stream.map(|_| Incoming::Usr1));
+    for incoming in sockets.into_iter() {
+      all = Box::new(all.select(incoming));
+    }
 
-    let mut binder = codec::SmtpProto::new(config.servername.clone(), config.archivers.clone());
+    let mut binder = codec::SmtpProto::new(config.servername.clone(), config.archivers.clone(), config.extensions.clone());
+    binder.set_max_message_size(config.max_message_size);
+    binder.set_unknown_recipients(config.unknown_recipients.clone());
+    binder.set_rules(config.rules.clone());
+    binder.set_tls(acceptor.clone(), config.require_tls);
     // let new_service = service::new_service(&handle);
     let server = all.for_each(move |m| {
       match m {
-        Incoming::Connection((socket, addr)) => {
+        Incoming::Connection(socket, addr, tls) => {
           debug!("incoming connection from {}", addr);
           let this_prefix = format!("{}-{:06x}", &prg_prefix, &connection_counter);
           // we need to pass this prefix to service, but service is stateless :/
           // i.e. re-implement without the use of service!
           connection_counter = connection_counter + 1;
-          let md = codec::make_emaildata(this_prefix,  config.archivers.clone());
-          let fut = binder.bind_transport(socket, md);
-          handle.spawn(fut.then(|_| Ok(()))); 
+          let md = codec::make_emaildata(this_prefix,  config.archivers.clone(), Some(client_ip(&addr)));
+          spawn_connection(&handle, &binder, socket, md, tls);
+          Ok(())
+        },
+        Incoming::Local(socket, tls) => {
+          debug!("incoming connection on local socket");
+          let this_prefix = format!("{}-{:06x}", &prg_prefix, &connection_counter);
+          connection_counter = connection_counter + 1;
+          let md = codec::make_emaildata(this_prefix,  config.archivers.clone(), None);
+          spawn_connection(&handle, &binder, socket, md, tls);
           Ok(())
         },
         Incoming::Usr1 => {
           debug!("signal usr1 receieved, reloading config {}", &config_file);
-          match config::read_config(&config_file) {
-            Ok(c) => {
+          let reloaded = config::read_config(&config_file)
+            .and_then(|c| check_reload(&bound, &c).map(|_| c))
+            .and_then(|c| reload_tls_acceptor(&config, &acceptor, &c).map(|a| (c, a)));
+          match reloaded {
+            Ok((c, a)) => {
               config = c;
+              acceptor = a;
               info!("reloaded config from {} on signal usr1", &config_file);
               binder.set_archivers(config.archivers.clone());
               binder.set_servername(config.servername.clone());
+              binder.set_capabilities(config.extensions.clone());
+              binder.set_max_message_size(config.max_message_size);
+              binder.set_unknown_recipients(config.unknown_recipients.clone());
+              binder.set_rules(config.rules.clone());
+              binder.set_tls(acceptor.clone(), config.require_tls);
             },
             Err(e) => {
               error!("Cannot use configuration file: {}, due to {}", config_file, e);
@@ -0,0 +1,225 @@
+use std::cmp;
+use std::fmt;
+use std::net::IpAddr;
+use regex::{self, Regex};
+
+use template;
+
+//
+// How an archiver matches recipients, written in the configuration as
+//   archive@corp.example          the address itself
+//   @corp.example                 any address in the domain
+//   journal+*@corp.example        a glob, each * is captured as %{1}, %{2}..
+//   /^journal\+(?P<dept>\w+)@/    a regex, captures by name and by number
+// Patterns are matched against the normalized address, ignoring case.
+// Rules use the same patterns for the sender, HELO name and header values.
+#[derive(Clone)]
+pub enum RecipientPattern {
+  Exact(String),
+  Domain(String),
+  Glob(String, Regex),
+  Regex(String, Regex),
+}
+
+impl RecipientPattern {
+
+  pub fn parse(pattern: &str) -> Result<RecipientPattern, String> {
+    if pattern.len() > 2 && pattern.starts_with('/') && pattern.ends_with('/') {
+      let source = &pattern[1..pattern.len() - 1];
+      match Regex::new(&format!("(?i){}", source)) {
+        Ok(re) => Ok(RecipientPattern::Regex(pattern.to_string(), re)),
+        Err(e) => Err(format!("invalid regex {}: {}", pattern, e)),
+      }
+    } else if pattern.starts_with('@') {
+      Ok(RecipientPattern::Domain(pattern[1..].to_lowercase()))
+    } else if pattern.contains('*') || pattern.contains('?') {
+      let mut source = "(?i)^".to_string();
+      let mut literal = String::new();
+      for c in pattern.chars() {
+        match c {
+          '*' | '?' => {
+            source.push_str(&regex::escape(&literal));
+            literal.clear();
+            source.push_str(if c == '*' { "(.*)" } else { "." });
+          },
+          _ => literal.push(c),
+        }
+      }
+      source.push_str(&regex::escape(&literal));
+      source.push('$');
+      match Regex::new(&source) {
+        Ok(re) => Ok(RecipientPattern::Glob(pattern.to_string(), re)),
+        Err(e) => Err(format!("invalid glob {}: {}", pattern, e)),
+      }
+    } else {
+      Ok(RecipientPattern::Exact(pattern.to_string()))
+    }
+  }
+
+  // Captures of a matching normalized address, None if it does not match
+  pub fn matches(&self, normalized: &str) -> Option<Vec<(String, String)>> {
+    match *self {
+      RecipientPattern::Exact(ref address) => {
+        if address == normalized { Some(Vec::new()) } else { None }
+      },
+      RecipientPattern::Domain(ref domain) => {
+        match normalized.rfind('@') {
+          Some(i) if &normalized[i + 1..] == domain.as_str() => Some(Vec::new()),
+          _ => None,
+        }
+      },
+      RecipientPattern::Glob(_, ref re) | RecipientPattern::Regex(_, ref re) => {
+        re.captures(normalized).map(|caps| {
+          let mut captures = Vec::new();
+          for (i, name) in re.capture_names().enumerate().skip(1) {
+            if let Some(value) = caps.get(i).map(|m| m.as_str()) {
+              captures.push((i.to_string(), value.to_string()));
+              if let Some(name) = name {
+                captures.push((name.to_string(), value.to_string()));
+              }
+            }
+          }
+          captures
+        })
+      },
+    }
+  }
+
+  // The variables a match provides, to check archive_path against
+  pub fn variables(&self) -> Vec<String> {
+    match *self {
+      RecipientPattern::Exact(_) | RecipientPattern::Domain(_) => Vec::new(),
+      RecipientPattern::Glob(_, ref re) | RecipientPattern::Regex(_, ref re) => {
+        let mut variables = Vec::new();
+        for (i, name) in re.capture_names().enumerate().skip(1) {
+          variables.push(i.to_string());
+          if let Some(name) = name {
+            variables.push(name.to_string());
+          }
+        }
+        variables
+      },
+    }
+  }
+}
+
+impl fmt::Display for RecipientPattern {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      RecipientPattern::Exact(ref address) => write!(f, "{}", address),
+      RecipientPattern::Domain(ref domain) => write!(f, "@{}", domain),
+      RecipientPattern::Glob(ref pattern, _) | RecipientPattern::Regex(ref pattern, _) => write!(f, "{}", pattern),
+    }
+  }
+}
+
+// A network as "192.0.2.0/24" or "2001:db8::/32"; a plain address is a
+// network of its own
+#[derive(Clone)]
+pub struct Cidr {
+  network: IpAddr,
+  prefix: u32,
+}
+
+impl Cidr {
+
+  pub fn parse(cidr: &str) -> Result<Cidr, String> {
+    let (address, prefix) = match cidr.find('/') {
+      Some(i) => (&cidr[..i], Some(&cidr[i + 1..])),
+      None => (cidr, None),
+    };
+    let network: IpAddr = match address.parse() {
+      Ok(a) => a,
+      Err(_) => return Err(format!("invalid network address {}", cidr)),
+    };
+    let bits = match network {
+      IpAddr::V4(_) => 32,
+      IpAddr::V6(_) => 128,
+    };
+    let prefix = match prefix.map(|p| p.parse::<u32>()) {
+      None => bits,
+      Some(Ok(p)) if p <= bits => p,
+      _ => return Err(format!("invalid prefix length in {}", cidr)),
+    };
+    Ok(Cidr { network: network, prefix: prefix })
+  }
+
+  pub fn contains(&self, address: &IpAddr) -> bool {
+    let (network, address) = match (self.network, *address) {
+      (IpAddr::V4(n), IpAddr::V4(a)) => (n.octets().to_vec(), a.octets().to_vec()),
+      (IpAddr::V6(n), IpAddr::V6(a)) => (n.octets().to_vec(), a.octets().to_vec()),
+      _ => return false,
+    };
+    let mut bits = self.prefix;
+    for (n, a) in network.iter().zip(address.iter()) {
+      if bits == 0 {
+        break;
+      }
+      let mask = if bits >= 8 { 0xff } else { 0xffu8 << (8 - bits) };
+      if n & mask != a & mask {
+        return false;
+      }
+      bits = bits - cmp::min(bits, 8);
+    }
+    true
+  }
+}
+
+impl fmt::Display for Cidr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}/{}", self.network, self.prefix)
+  }
+}
+
+// Names of the %{name} variables used in an archive_path
+pub fn path_variables(path: &str) -> Vec<String> {
+  let mut variables = Vec::new();
+  let mut rest = path;
+  while let Some(i) = rest.find("%{") {
+    match rest[i + 2..].find('}') {
+      Some(j) => {
+        variables.push(rest[i + 2..i + 2 + j].to_string());
+        rest = &rest[i + 3 + j..];
+      },
+      None => break,
+    }
+  }
+  variables
+}
+
+// Replace %{name} in an archive_path with captured values, before it is
+// given to strftime; values cannot climb out of the archive or add
+// strftime codes.
+pub fn expand(path: &str, captures: &[(String, String)]) -> String {
+  let mut expanded = path.to_string();
+  for &(ref name, ref value) in captures.iter() {
+    expanded = expanded.replace(&format!("%{{{}}}", name), &template::sanitize(value));
+  }
+  expanded
+}
+
+#[cfg(test)]
+mod tests {
+  use super::expand;
+
+  fn captures(value: &str) -> Vec<(String, String)> {
+    vec![("dept".to_string(), value.to_string())]
+  }
+
+  // Captures come from the recipient, which the client chose
+  #[test]
+  fn captures_stay_one_component() {
+    for value in ["..", ".", "a/b", "../%Y", "%Y", "{prefix}", ""].iter() {
+      let expanded = expand("/archive/%{dept}/%Y", &captures(value));
+      let components: Vec<&str> = expanded.split('/').collect();
+      assert_eq!(components.len(), 4, "{:?} gave {}", value, expanded);
+      assert!(components[2] != "" && components[2] != "." && components[2] != "..", "{:?} gave {}", value, expanded);
+      assert!(!components[2].contains('%') && !components[2].contains('{'), "{:?} gave {}", value, expanded);
+    }
+  }
+
+  #[test]
+  fn unknown_captures_are_left() {
+    assert_eq!(expand("/archive/%{other}/%{dept}", &captures("sales")), "/archive/%{other}/sales");
+  }
+}
@@ -0,0 +1,210 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use zstd;
+
+use config::Compression;
+use storage::{Delivery, Envelope, SpoolError, Storage};
+
+//
+// Compression in front of a backend; the message is compressed as it is
+// appended, and what comes out is handed on to the backend's delivery.
+// File names get ".gz" or ".zst" added.
+pub struct Compressed {
+  pub storage: Box<Storage>,
+  pub compression: Compression,
+}
+
+enum Encoder {
+  Gzip(GzEncoder<Vec<u8>>),
+  Zstd(zstd::stream::Encoder<Vec<u8>>),
+}
+
+struct CompressedDelivery {
+  encoder: Encoder,
+  delivery: Box<Delivery>,
+}
+
+impl Storage for Compressed {
+  fn begin (&self, envelope: &Envelope) -> Result<Box<Delivery>, SpoolError> {
+    let mut envelope = envelope.clone();
+    envelope.file_name = format!("{}{}", envelope.file_name, extension(&self.compression));
+    let delivery = try!(self.storage.begin(&envelope));
+    compressed(delivery, &self.compression)
+  }
+
+  fn remove (&self, location: &str) {
+    self.storage.remove(location)
+  }
+}
+
+impl Delivery for CompressedDelivery {
+  fn append (&mut self, data: &[u8]) -> Result<(), SpoolError> {
+    // the encoders write into a Vec, which is emptied into the delivery
+    let buf = match self.encoder {
+      Encoder::Gzip(ref mut e) => {
+        try!(e.write_all(data).map_err(|e| SpoolError::new("Cannot compress message".to_string(), &e)));
+        e.get_mut()
+      },
+      Encoder::Zstd(ref mut e) => {
+        try!(e.write_all(data).map_err(|e| SpoolError::new("Cannot compress message".to_string(), &e)));
+        e.get_mut()
+      },
+    };
+    let result = if buf.len() > 0 { self.delivery.append(buf) } else { Ok(()) };
+    buf.clear();
+    result
+  }
+
+  fn commit (self: Box<Self>) -> Result<String, SpoolError> {
+    let CompressedDelivery { encoder, mut delivery } = *self;
+    let rest = match encoder {
+      Encoder::Gzip(e) => e.finish(),
+      Encoder::Zstd(e) => e.finish(),
+    };
+    let appended = match rest {
+      Ok(buf) => delivery.append(&buf),
+      Err(e) => Err(SpoolError::new("Cannot compress message".to_string(), &e)),
+    };
+    match appended {
+      Ok(_) => delivery.commit(),
+      Err(e) => {
+        delivery.abort();
+        Err(e)
+      }
+    }
+  }
+
+  fn abort (self: Box<Self>) {
+    self.delivery.abort()
+  }
+}
+
+// Compress what goes to delivery
+fn compressed (delivery: Box<Delivery>, compression: &Compression) -> Result<Box<Delivery>, SpoolError> {
+  let encoder = match *compression {
+    Compression::Gzip(level) => Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::new(level))),
+    Compression::Zstd(level) => match zstd::stream::Encoder::new(Vec::new(), level) {
+      Ok(e) => Encoder::Zstd(e),
+      Err(e) => {
+        delivery.abort();
+        return Err(SpoolError::new("Cannot start zstd compression".to_string(), &e));
+      },
+    },
+    Compression::None => return Ok(delivery),
+  };
+  Ok(Box::new(CompressedDelivery { encoder: encoder, delivery: delivery }))
+}
+
+pub fn extension (compression: &Compression) -> &'static str {
+  match *compression {
+    Compression::None => "",
+    Compression::Gzip(_) => ".gz",
+    Compression::Zstd(_) => ".zst",
+  }
+}
+
+// Write an archived message to stdout, decompressed; the format is told by
+// its magic number, so uncompressed messages are copied as they are
+pub fn decompress (path: &str) -> io::Result<()> {
+  let file = try!(File::open(path));
+  let stdout = io::stdout();
+  let mut output = stdout.lock();
+  write_decompressed(file, &mut output)
+}
+
+pub fn write_decompressed<R: Read, W: Write> (mut input: R, output: &mut W) -> io::Result<()> {
+  let mut magic = [0u8; 4];
+  let mut n = 0;
+  while n < magic.len() {
+    match try!(input.read(&mut magic[n..])) {
+      0 => break,
+      m => n = n + m,
+    }
+  }
+  let mut input = io::Cursor::new(magic[..n].to_vec()).chain(input);
+  if magic[..n].starts_with(&[0x1f, 0x8b]) {
+    try!(io::copy(&mut GzDecoder::new(input), output));
+  } else if magic[..n] == [0x28, 0xb5, 0x2f, 0xfd] {
+    try!(zstd::stream::copy_decode(input, &mut *output));
+  } else {
+    try!(io::copy(&mut input, output));
+  }
+  output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+  use std::io;
+  use std::rc::Rc;
+
+  use config::Compression;
+  use storage::{Delivery, SpoolError};
+  use super::{compressed, write_decompressed};
+
+  // Keeps what is appended, where the test can see it
+  struct MemoryDelivery {
+    stored: Rc<RefCell<Vec<u8>>>,
+  }
+
+  impl Delivery for MemoryDelivery {
+    fn append (&mut self, data: &[u8]) -> Result<(), SpoolError> {
+      self.stored.borrow_mut().extend_from_slice(data);
+      Ok(())
+    }
+
+    fn commit (self: Box<Self>) -> Result<String, SpoolError> {
+      Ok("memory".to_string())
+    }
+
+    fn abort (self: Box<Self>) {}
+  }
+
+  // Text with CRLF, bare LF and CR, 8-bit octets, and enough of it to
+  // span several encoder buffers
+  fn message() -> Vec<u8> {
+    let mut message = b"Message-ID: <round-trip@example.com>\r\nSubject: test\r\n\r\n".to_vec();
+    for i in 0..20000u32 {
+      message.extend_from_slice(format!("line {} \u{e6}\u{f8}\u{e5}\r\n", i).as_bytes());
+      message.push((i % 256) as u8);
+      message.extend_from_slice(if i % 7 == 0 { b"\n" } else { b"\r" });
+    }
+    message
+  }
+
+  fn round_trip (compression: Compression) {
+    let original = message();
+    let stored = Rc::new(RefCell::new(Vec::new()));
+    let mut delivery = compressed(Box::new(MemoryDelivery { stored: stored.clone() }), &compression).ok().unwrap();
+    for piece in original.chunks(1000) {
+      delivery.append(piece).ok().unwrap();
+    }
+    delivery.commit().ok().unwrap();
+    assert!(stored.borrow().len() < original.len());
+    let mut recovered = Vec::new();
+    write_decompressed(io::Cursor::new(stored.borrow().clone()), &mut recovered).unwrap();
+    assert!(recovered == original);
+  }
+
+  #[test]
+  fn gzip_round_trip() {
+    round_trip(Compression::Gzip(6));
+  }
+
+  #[test]
+  fn zstd_round_trip() {
+    round_trip(Compression::Zstd(3));
+  }
+
+  #[test]
+  fn uncompressed_is_copied() {
+    let original = message();
+    let mut recovered = Vec::new();
+    write_decompressed(io::Cursor::new(original.clone()), &mut recovered).unwrap();
+    assert!(recovered == original);
+  }
+}
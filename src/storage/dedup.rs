@@ -0,0 +1,115 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use sha2::{Digest, Sha256};
+use time;
+
+use storage::{self, Delivery, Envelope, SpoolError, Storage, TmpFile};
+
+//
+// Content addressed; a message is stored once in blob_path, named by the
+// SHA-256 of its content, as blob_path/ab/cd/abcd...eml. Each delivery
+// writes a small metadata file, file_name in archive_path, with the
+// envelope and the blob it points at; the same message journaled for fifty
+// recipients costs one copy and fifty metadata files.
+pub struct Dedup {
+  pub blob_path: String,
+}
+
+struct DedupDelivery {
+  spool: TmpFile,       // the blob, in blob_path/tmp until its hash is known
+  hasher: Sha256,
+  size: u64,
+  blob_path: String,
+  meta_path: String,
+  meta: String,         // the envelope part of the metadata
+}
+
+impl Storage for Dedup {
+  fn begin (&self, envelope: &Envelope) -> Result<Box<Delivery>, SpoolError> {
+    let tmp_dir = format!("{}/tmp", self.blob_path);
+    for dir in [&tmp_dir, &envelope.directory].iter() {
+      if let Err(e) = fs::create_dir_all(dir) {
+        return Err(SpoolError::new(format!("Cannot create {}", dir), &e));
+      }
+    }
+    let path = storage::tmp_path(&format!("{}/blob", tmp_dir));
+    info!("Spooling mail to {}", path);
+    let spool = match File::create(&path) {
+      Ok(file) => TmpFile::new(file, path),
+      Err(e) => return Err(SpoolError::new(format!("Cannot create {}", path), &e)),
+    };
+    let received = time::strftime("%a, %d %b %Y %H:%M:%S %z", &envelope.received).unwrap_or("".to_string());
+    let recipients: Vec<String> = envelope.recipients.iter().map(|r| field(r)).collect();
+    let meta = format!("Message-ID: <{}>\nSender: <{}>\nRecipients: {}\nReceived: {}\n",
+                       field(&envelope.message_id), field(&envelope.sender), recipients.join(", "), field(&received));
+    Ok(Box::new(DedupDelivery { spool: spool, hasher: Sha256::default(), size: 0, blob_path: self.blob_path.clone(),
+                                meta_path: format!("{}/{}", envelope.directory, envelope.file_name), meta: meta }))
+  }
+
+  // Only the metadata file goes, the blob may be shared with other messages
+  fn remove (&self, location: &str) {
+    storage::remove(location)
+  }
+}
+
+impl Delivery for DedupDelivery {
+  fn append (&mut self, data: &[u8]) -> Result<(), SpoolError> {
+    self.hasher.input(data);
+    self.size = self.size + data.len() as u64;
+    self.spool.file.write_all(data).map_err(|e| SpoolError::new(format!("Cannot write {}", self.spool.path), &e))
+  }
+
+  fn commit (self: Box<Self>) -> Result<String, SpoolError> {
+    let mut delivery = *self;
+    let hash: String = delivery.hasher.result().iter().map(|b| format!("{:02x}", b)).collect();
+    let blob = try!(store_blob(&mut delivery.spool, hash, &delivery.blob_path));
+    let meta = format!("{}Size: {}\nBlob: {}\n", delivery.meta, delivery.size, field(&blob));
+    write_meta(&delivery.meta_path, &meta)
+  }
+
+  // the blob in blob_path/tmp goes with the delivery
+  fn abort (self: Box<Self>) {}
+}
+
+// A value for the metadata file, on one line; the Message-ID comes from
+// the client and may hold a bare CR or LF, which could add lines
+fn field (value: &str) -> String {
+  value.replace("\\", "\\\\").replace("\r", "\\r").replace("\n", "\\n")
+}
+
+// Move the spooled blob to its place, unless a copy is there already, in
+// which case it is left to be removed with the delivery
+fn store_blob (spool: &mut TmpFile, hash: String, blob_path: &str) -> Result<String, SpoolError> {
+  let dir = format!("{}/{}/{}", blob_path, &hash[..2], &hash[2..4]);
+  let blob = format!("{}/{}.eml", dir, hash);
+  if fs::metadata(&blob).is_ok() {
+    debug!("Message already stored as {}", blob);
+    return Ok(blob);
+  }
+  if let Err(e) = spool.file.sync_all() {
+    return Err(SpoolError::new(format!("Cannot sync {}", spool.path), &e));
+  }
+  if let Err(e) = fs::create_dir_all(&dir) {
+    return Err(SpoolError::new(format!("Cannot create {}", dir), &e));
+  }
+  if let Err(e) = fs::rename(&spool.path, &blob) {
+    return Err(SpoolError::new(format!("Cannot rename {} to {}", spool.path, blob), &e));
+  }
+  spool.done();
+  try!(storage::sync_dir(&blob));
+  Ok(blob)
+}
+
+fn write_meta (meta_path: &str, meta: &str) -> Result<String, SpoolError> {
+  let tmp = storage::tmp_path(meta_path);
+  let written = OpenOptions::new().write(true).create_new(true).open(&tmp).and_then(|mut f| {
+    try!(f.write_all(meta.as_bytes()));
+    f.sync_all()
+  });
+  if let Err(e) = written {
+    let _ = fs::remove_file(&tmp);
+    return Err(SpoolError::new(format!("Cannot write {}", tmp), &e));
+  }
+  storage::install(&tmp, meta_path)
+}
@@ -0,0 +1,72 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use storage::{self, Delivery, Envelope, SpoolError, Storage, TmpFile};
+
+//
+// One .eml file per message, file_name in the archive_path directory.
+// The message is written to a .tmp file next to it, which is synced and
+// given its name on commit; an existing file is never replaced, see
+// storage::install.
+pub struct Eml;
+
+struct EmlDelivery {
+  spool: TmpFile,       // the .tmp file
+  final_path: String,
+}
+
+fn create (path: &str) -> io::Result<File> {
+  OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+impl Storage for Eml {
+  fn begin (&self, envelope: &Envelope) -> Result<Box<Delivery>, SpoolError> {
+    let final_path = format!("{}/{}", envelope.directory, envelope.file_name);
+    let path = storage::tmp_path(&final_path);
+    info!("Spooling mail to {}", path);
+    let file = match create(&path) {
+      Ok(file) => file,
+      Err(_) => {
+        // perhaps dir is not created...
+        if let Some(dir) = Path::new(&path).parent() {
+          if let Err(e) = fs::create_dir_all(dir) {
+            return Err(SpoolError::new(format!("Cannot create {}", dir.display()), &e));
+          }
+        }
+        // now try again before failing.
+        match create(&path) {
+          Ok(file) => file,
+          Err(e) => return Err(SpoolError::new(format!("Cannot create {}", path), &e)),
+        }
+      }
+    };
+    Ok(Box::new(EmlDelivery { spool: TmpFile::new(file, path), final_path: final_path }))
+  }
+
+  fn remove (&self, location: &str) {
+    storage::remove(location)
+  }
+}
+
+impl Delivery for EmlDelivery {
+  fn append (&mut self, data: &[u8]) -> Result<(), SpoolError> {
+    self.spool.file.write_all(data).map_err(|e| SpoolError::new(format!("Cannot write {}", self.spool.path), &e))
+  }
+
+  fn commit (self: Box<Self>) -> Result<String, SpoolError> {
+    let mut delivery = *self;
+    if let Err(e) = delivery.spool.file.sync_all() {
+      return Err(SpoolError::new(format!("Cannot sync {}", delivery.spool.path), &e));
+    }
+    // install removes the .tmp file, whether it succeeds or not
+    let result = storage::install(&delivery.spool.path, &delivery.final_path);
+    delivery.spool.done();
+    result
+  }
+
+  // the .tmp file goes with the delivery
+  fn abort (self: Box<Self>) {}
+}
@@ -0,0 +1,244 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use openssl::error::ErrorStack;
+use openssl::rand::rand_bytes;
+use openssl::rsa::{Padding, Rsa};
+use openssl::symm::{Cipher, Crypter, Mode};
+
+use storage::{compress, Delivery, Envelope, SpoolError, Storage};
+
+//
+// Encryption in front of a backend. Every message gets its own AES-256-GCM
+// key, which is stored with it encrypted to the archiver's RSA public key
+// (OAEP); the daemon cannot read back what it has written. Files are
+//   "MAENC1", key length (2 octets, big endian), encrypted key, IV (12),
+//   the encrypted message, GCM tag (16)
+// and get ".enc" added to their names.
+pub struct Encrypted {
+  pub storage: Box<Storage>,
+  pub public_key: Vec<u8>,    // PEM
+}
+
+struct EncryptedDelivery {
+  crypter: Crypter,
+  delivery: Box<Delivery>,
+}
+
+const MAGIC: &'static [u8] = b"MAENC1";
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+impl Storage for Encrypted {
+  fn begin (&self, envelope: &Envelope) -> Result<Box<Delivery>, SpoolError> {
+    let mut envelope = envelope.clone();
+    envelope.file_name = format!("{}.enc", envelope.file_name);
+    let (header, crypter) = try!(start(&self.public_key).map_err(|e| SpoolError::other(&format!("Cannot start encryption: {}", e))));
+    let mut delivery = try!(self.storage.begin(&envelope));
+    if let Err(e) = delivery.append(&header) {
+      delivery.abort();
+      return Err(e);
+    }
+    Ok(Box::new(EncryptedDelivery { crypter: crypter, delivery: delivery }))
+  }
+
+  fn remove (&self, location: &str) {
+    self.storage.remove(location)
+  }
+}
+
+impl Delivery for EncryptedDelivery {
+  fn append (&mut self, data: &[u8]) -> Result<(), SpoolError> {
+    let mut out = vec![0u8; data.len() + Cipher::aes_256_gcm().block_size()];
+    match self.crypter.update(data, &mut out) {
+      Ok(n) => self.delivery.append(&out[..n]),
+      Err(e) => Err(SpoolError::other(&format!("Cannot encrypt message: {}", e))),
+    }
+  }
+
+  fn commit (self: Box<Self>) -> Result<String, SpoolError> {
+    let EncryptedDelivery { mut crypter, mut delivery } = *self;
+    let mut rest = vec![0u8; Cipher::aes_256_gcm().block_size() + TAG_LEN];
+    let finished = crypter.finalize(&mut rest).and_then(|n| {
+      try!(crypter.get_tag(&mut rest[n..n + TAG_LEN]));
+      Ok(n + TAG_LEN)
+    });
+    let appended = match finished {
+      Ok(n) => delivery.append(&rest[..n]),
+      Err(e) => Err(SpoolError::other(&format!("Cannot encrypt message: {}", e))),
+    };
+    match appended {
+      Ok(_) => delivery.commit(),
+      Err(e) => {
+        delivery.abort();
+        Err(e)
+      }
+    }
+  }
+
+  fn abort (self: Box<Self>) {
+    self.delivery.abort()
+  }
+}
+
+// A fresh key and IV, and the header carrying them
+fn start (public_key: &[u8]) -> Result<(Vec<u8>, Crypter), ErrorStack> {
+  let rsa = try!(Rsa::public_key_from_pem(public_key));
+  let mut key = [0u8; 32];
+  let mut iv = [0u8; IV_LEN];
+  try!(rand_bytes(&mut key));
+  try!(rand_bytes(&mut iv));
+  let mut wrapped = vec![0u8; rsa.size() as usize];
+  let n = try!(rsa.public_encrypt(&key, &mut wrapped, Padding::PKCS1_OAEP));
+  let mut header = MAGIC.to_vec();
+  header.push((n >> 8) as u8);
+  header.push(n as u8);
+  header.extend_from_slice(&wrapped[..n]);
+  header.extend_from_slice(&iv);
+  let crypter = try!(Crypter::new(Cipher::aes_256_gcm(), Mode::Encrypt, &key, Some(&iv)));
+  Ok((header, crypter))
+}
+
+// Check a public key can be used, when the configuration is read
+pub fn check_public_key (pem: &[u8]) -> Result<(), String> {
+  Rsa::public_key_from_pem(pem).map(|_| ()).map_err(|e| e.to_string())
+}
+
+// Write an archived message to stdout, decrypted with the private key in
+// key_path, and decompressed if it was compressed. Nothing is written
+// unless the message is intact.
+pub fn decrypt (path: &str, key_path: &str) -> io::Result<()> {
+  let mut pem = Vec::new();
+  try!(File::open(key_path).and_then(|mut f| f.read_to_end(&mut pem)));
+  let mut data = Vec::new();
+  try!(File::open(path).and_then(|mut f| f.read_to_end(&mut data)));
+  let message = try!(open(&pem, &data));
+  let stdout = io::stdout();
+  let mut output = stdout.lock();
+  compress::write_decompressed(io::Cursor::new(message), &mut output)
+}
+
+fn open (pem: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+  let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+  if data.len() < MAGIC.len() + 2 || &data[..MAGIC.len()] != MAGIC {
+    return Err(invalid("not an encrypted message"));
+  }
+  let key_len = ((data[MAGIC.len()] as usize) << 8) | data[MAGIC.len() + 1] as usize;
+  let key_start = MAGIC.len() + 2;
+  let iv_start = key_start + key_len;
+  let body_start = iv_start + IV_LEN;
+  if data.len() < body_start + TAG_LEN {
+    return Err(invalid("encrypted message is truncated"));
+  }
+  let body_end = data.len() - TAG_LEN;
+  let rsa = try!(Rsa::private_key_from_pem(pem).map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+  let mut key = vec![0u8; rsa.size() as usize];
+  let n = try!(rsa.private_decrypt(&data[key_start..iv_start], &mut key, Padding::PKCS1_OAEP)
+               .map_err(|_| invalid("message was not encrypted to this key")));
+  let decrypted = Crypter::new(Cipher::aes_256_gcm(), Mode::Decrypt, &key[..n], Some(&data[iv_start..body_start])).and_then(|mut crypter| {
+    try!(crypter.set_tag(&data[body_end..]));
+    let mut message = vec![0u8; body_end - body_start + Cipher::aes_256_gcm().block_size()];
+    let mut len = try!(crypter.update(&data[body_start..body_end], &mut message));
+    len = len + try!(crypter.finalize(&mut message[len..]));
+    message.truncate(len);
+    Ok(message)
+  });
+  decrypted.map_err(|_| invalid("message has been altered, or the key is wrong"))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+  use std::rc::Rc;
+  use openssl::pkey::Private;
+  use openssl::rsa::Rsa;
+  use time;
+
+  use storage::{Delivery, Envelope, SpoolError, Storage};
+  use super::{open, Encrypted};
+
+  // Keeps what is appended, where the test can see it
+  struct MemoryStorage {
+    stored: Rc<RefCell<Vec<u8>>>,
+  }
+
+  struct MemoryDelivery {
+    stored: Rc<RefCell<Vec<u8>>>,
+  }
+
+  impl Storage for MemoryStorage {
+    fn begin (&self, _: &Envelope) -> Result<Box<Delivery>, SpoolError> {
+      Ok(Box::new(MemoryDelivery { stored: self.stored.clone() }))
+    }
+
+    fn remove (&self, _: &str) {}
+  }
+
+  impl Delivery for MemoryDelivery {
+    fn append (&mut self, data: &[u8]) -> Result<(), SpoolError> {
+      self.stored.borrow_mut().extend_from_slice(data);
+      Ok(())
+    }
+
+    fn commit (self: Box<Self>) -> Result<String, SpoolError> {
+      Ok("memory".to_string())
+    }
+
+    fn abort (self: Box<Self>) {}
+  }
+
+  fn message() -> Vec<u8> {
+    let mut message = b"Message-ID: <encrypted@example.com>\r\nSubject: test\r\n\r\n".to_vec();
+    for i in 0..5000u32 {
+      message.extend_from_slice(format!("line {} \u{e6}\u{f8}\u{e5}\r\n", i).as_bytes());
+      message.push((i % 256) as u8);
+    }
+    message
+  }
+
+  // The message encrypted to key, as stored
+  fn encrypted (key: &Rsa<Private>, message: &[u8]) -> Vec<u8> {
+    let stored = Rc::new(RefCell::new(Vec::new()));
+    let storage = Encrypted { storage: Box::new(MemoryStorage { stored: stored.clone() }), public_key: key.public_key_to_pem().unwrap() };
+    let envelope = Envelope { directory: "archive".to_string(), file_name: "message.eml".to_string(), sender: "".to_string(),
+                              recipients: Vec::new(), message_id: "".to_string(), received: time::now_utc() };
+    let mut delivery = storage.begin(&envelope).ok().unwrap();
+    for piece in message.chunks(1000) {
+      delivery.append(piece).ok().unwrap();
+    }
+    delivery.commit().ok().unwrap();
+    let data = stored.borrow().clone();
+    data
+  }
+
+  #[test]
+  fn round_trip() {
+    let key = Rsa::generate(2048).unwrap();
+    let original = message();
+    let data = encrypted(&key, &original);
+    assert!(data.starts_with(b"MAENC1"));
+    assert!(open(&key.private_key_to_pem().unwrap(), &data).unwrap() == original);
+  }
+
+  #[test]
+  fn altered_message_is_rejected() {
+    let key = Rsa::generate(2048).unwrap();
+    let pem = key.private_key_to_pem().unwrap();
+    let data = encrypted(&key, &message());
+    // a byte of the encrypted message, after the header, and of the tag
+    let header_len = 6 + 2 + key.size() as usize + 12;
+    for &i in [header_len + 100, data.len() - 1].iter() {
+      let mut altered = data.clone();
+      altered[i] = altered[i] ^ 0x01;
+      assert!(open(&pem, &altered).is_err());
+    }
+  }
+
+  #[test]
+  fn wrong_key_is_rejected() {
+    let key = Rsa::generate(2048).unwrap();
+    let other = Rsa::generate(2048).unwrap();
+    let data = encrypted(&key, &message());
+    assert!(open(&other.private_key_to_pem().unwrap(), &data).is_err());
+  }
+}
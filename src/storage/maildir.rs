@@ -0,0 +1,81 @@
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use libc;
+use time;
+
+use config;
+use storage::{self, Delivery, Envelope, SpoolError, Storage, TmpFile};
+
+//
+// Maildir, the archive_path is the root holding tmp, new and cur; file_name
+// is not used, Maildir names messages itself. A message is written in tmp
+// and renamed into new on commit, see https://cr.yp.to/proto/maildir.html
+pub struct Maildir;
+
+struct MaildirDelivery {
+  spool: TmpFile,       // in tmp
+  final_path: String,   // in new
+}
+
+// deliveries made by this process, part of the unique name
+static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// "1490000000.M123456P4711Q42.host", with / and : in the host name
+// written as \057 and \072
+fn unique_name() -> String {
+  let now = time::get_time();
+  let pid = unsafe { libc::getpid() };
+  let counter = COUNTER.fetch_add(1, Ordering::SeqCst);
+  let host = config::libc_gethostname().replace("/", "\\057").replace(":", "\\072");
+  format!("{}.M{}P{}Q{}.{}", now.sec, now.nsec / 1000, pid, counter, host)
+}
+
+impl Storage for Maildir {
+  fn begin (&self, envelope: &Envelope) -> Result<Box<Delivery>, SpoolError> {
+    for sub in ["tmp", "new", "cur"].iter() {
+      let dir = format!("{}/{}", envelope.directory, sub);
+      if let Err(e) = fs::create_dir_all(&dir) {
+        return Err(SpoolError::new(format!("Cannot create {}", dir), &e));
+      }
+    }
+    let name = unique_name();
+    let path = format!("{}/tmp/{}", envelope.directory, name);
+    let final_path = format!("{}/new/{}", envelope.directory, name);
+    info!("Spooling mail to {}", path);
+    match File::create(&path) {
+      Ok(file) => Ok(Box::new(MaildirDelivery { spool: TmpFile::new(file, path), final_path: final_path })),
+      Err(e) => Err(SpoolError::new(format!("Cannot create {}", path), &e)),
+    }
+  }
+
+  fn remove (&self, location: &str) {
+    storage::remove(location)
+  }
+}
+
+impl Delivery for MaildirDelivery {
+  fn append (&mut self, data: &[u8]) -> Result<(), SpoolError> {
+    self.spool.file.write_all(data).map_err(|e| SpoolError::new(format!("Cannot write {}", self.spool.path), &e))
+  }
+
+  fn commit (self: Box<Self>) -> Result<String, SpoolError> {
+    let mut delivery = *self;
+    if let Err(e) = delivery.spool.file.sync_all() {
+      return Err(SpoolError::new(format!("Cannot sync {}", delivery.spool.path), &e));
+    }
+    if let Err(e) = fs::rename(&delivery.spool.path, &delivery.final_path) {
+      return Err(SpoolError::new(format!("Cannot rename {} to {}", delivery.spool.path, delivery.final_path), &e));
+    }
+    delivery.spool.done();
+    if let Err(e) = storage::sync_dir(&delivery.final_path) {
+      let _ = fs::remove_file(&delivery.final_path);
+      return Err(e);
+    }
+    Ok(delivery.final_path)
+  }
+
+  // the file in tmp goes with the delivery
+  fn abort (self: Box<Self>) {}
+}
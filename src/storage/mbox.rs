@@ -0,0 +1,217 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::time::SystemTime;
+use libc;
+use time;
+
+use storage::{self, Delivery, Envelope, SpoolError, Storage, TmpFile};
+
+//
+// mboxrd, messages are appended to the mailbox file_name in archive_path.
+// A message is first written to a .tmp file next to the mailbox, with
+// "From " quoting applied and line endings made LF, so the mailbox is
+// only locked, with fcntl and a .lock file, for the final append. A
+// mailbox locked by someone else fails the delivery, and the client is
+// told to try again later.
+pub struct Mbox;
+
+struct MboxDelivery {
+  spool: TmpFile,       // the .tmp file
+  mbox_path: String,
+  line_start: bool,     // the next octet starts a line
+}
+
+// when a .lock file is left over
+const STALE_DOTLOCK_SECS: u64 = 300;
+
+impl Storage for Mbox {
+  fn begin (&self, envelope: &Envelope) -> Result<Box<Delivery>, SpoolError> {
+    if let Err(e) = fs::create_dir_all(&envelope.directory) {
+      return Err(SpoolError::new(format!("Cannot create {}", envelope.directory), &e));
+    }
+    let mbox_path = format!("{}/{}", envelope.directory, envelope.file_name);
+    let path = storage::tmp_path(&mbox_path);
+    let mut spool = match OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path) {
+      Ok(file) => TmpFile::new(file, path),
+      Err(e) => return Err(SpoolError::new(format!("Cannot create {}", path), &e)),
+    };
+    // a quoted local part may hold spaces, which would end the sender on
+    // the "From " line for mbox readers
+    let sender = match envelope.sender.len() {
+      0 => "MAILER-DAEMON".to_string(),
+      _ => envelope.sender.chars().map(|c| if c.is_whitespace() || c.is_control() { '_' } else { c }).collect(),
+    };
+    let date = time::strftime("%a %b %e %H:%M:%S %Y", &envelope.received).unwrap_or("".to_string());
+    if let Err(e) = write!(spool.file, "From {} {}\n", sender, date) {
+      return Err(SpoolError::new(format!("Cannot write {}", spool.path), &e));
+    }
+    Ok(Box::new(MboxDelivery { spool: spool, mbox_path: mbox_path, line_start: true }))
+  }
+
+  // Locations are "mailbox#start-end"; the message can be taken out again
+  // as long as nothing was appended after it
+  fn remove (&self, location: &str) {
+    let (mbox_path, start, end) = match parse_location(location) {
+      Some(l) => l,
+      None => return,
+    };
+    match truncate(&mbox_path, start, end) {
+      Ok(true) => {},
+      Ok(false) => error!("Cannot remove {}, the mailbox was appended to since", location),
+      Err(e) => error!("Cannot remove {}: {}", location, e),
+    }
+  }
+}
+
+impl Delivery for MboxDelivery {
+  fn append (&mut self, data: &[u8]) -> Result<(), SpoolError> {
+    let mut quoted = Vec::with_capacity(data.len() + 1);
+    let mut rest = data;
+    while rest.len() > 0 {
+      let end = rest.iter().position(|&b| b == b'\n').map(|i| i + 1).unwrap_or(rest.len());
+      let (line, tail) = rest.split_at(end);
+      if self.line_start && is_from_line(line) {
+        quoted.push(b'>');
+      }
+      if line.ends_with(b"\r\n") {
+        quoted.extend_from_slice(&line[..line.len() - 2]);
+        quoted.push(b'\n');
+      } else {
+        quoted.extend_from_slice(line);
+      }
+      self.line_start = line.ends_with(b"\n");
+      rest = tail;
+    }
+    self.spool.file.write_all(&quoted).map_err(|e| SpoolError::new(format!("Cannot write {}", self.spool.path), &e))
+  }
+
+  // the .tmp file goes with the delivery, once appended or not
+  fn commit (self: Box<Self>) -> Result<String, SpoolError> {
+    let mut delivery = *self;
+    delivery.append_to_mbox()
+  }
+
+  fn abort (self: Box<Self>) {}
+}
+
+impl MboxDelivery {
+  // Copy the message to the end of the mailbox, followed by the empty line
+  // that separates messages; on failure the mailbox is cut back
+  fn append_to_mbox (&mut self) -> Result<String, SpoolError> {
+    let ending: &[u8] = if self.line_start { b"\n" } else { b"\n\n" };
+    try!(self.spool.file.write_all(ending).and_then(|_| self.spool.file.seek(SeekFrom::Start(0)))
+      .map_err(|e| SpoolError::new(format!("Cannot write {}", self.spool.path), &e)));
+
+    let mut mbox = try!(OpenOptions::new().write(true).create(true).open(&self.mbox_path)
+      .map_err(|e| SpoolError::new(format!("Cannot open {}", self.mbox_path), &e)));
+    try!(lock(&mbox, &self.mbox_path).map_err(|e| SpoolError::new(format!("Cannot lock {}", self.mbox_path), &e)));
+    let result = self.append_locked(&mut mbox);
+    unlock(&self.mbox_path);
+    result.map_err(|e| SpoolError::new(format!("Cannot append to {}", self.mbox_path), &e))
+  }
+
+  fn append_locked (&mut self, mbox: &mut File) -> io::Result<String> {
+    let start = try!(mbox.seek(SeekFrom::End(0)));
+    if let Err(e) = copy(&mut self.spool.file, mbox).and_then(|_| mbox.sync_all()) {
+      let _ = mbox.set_len(start);
+      return Err(e);
+    }
+    let end = try!(mbox.seek(SeekFrom::Current(0)));
+    Ok(format!("{}#{}-{}", self.mbox_path, start, end))
+  }
+}
+
+// Cut the mailbox back to start, if it still ends at end
+fn truncate(mbox_path: &str, start: u64, end: u64) -> io::Result<bool> {
+  let file = try!(OpenOptions::new().write(true).open(mbox_path));
+  try!(lock(&file, mbox_path));
+  let result = file.metadata().and_then(|m| {
+    if m.len() == end {
+      file.set_len(start).map(|_| true)
+    } else {
+      Ok(false)
+    }
+  });
+  unlock(mbox_path);
+  result
+}
+
+// Lines starting with ">*From " get another ">", mboxrd
+fn is_from_line(line: &[u8]) -> bool {
+  let unquoted = match line.iter().position(|&b| b != b'>') {
+    Some(i) => &line[i..],
+    None => return false,
+  };
+  unquoted.starts_with(b"From ")
+}
+
+fn copy(from: &mut File, to: &mut File) -> io::Result<()> {
+  let mut buf = [0u8; 65536];
+  loop {
+    let n = try!(from.read(&mut buf));
+    if n == 0 {
+      return Ok(());
+    }
+    try!(to.write_all(&buf[..n]));
+  }
+}
+
+fn parse_location(location: &str) -> Option<(String, u64, u64)> {
+  let i = match location.rfind('#') {
+    Some(i) => i,
+    None => return None,
+  };
+  let mut range = location[i + 1..].split('-');
+  match (range.next().map(|s| s.parse::<u64>()), range.next().map(|s| s.parse::<u64>())) {
+    (Some(Ok(start)), Some(Ok(end))) => Some((location[..i].to_string(), start, end)),
+    _ => None,
+  }
+}
+
+// Take both the .lock file, for mail readers using dotlocking, and an
+// fcntl lock on the mailbox. This runs on the reactor thread, which must
+// not wait, so there is a single attempt. The fcntl lock goes with the
+// file when it is closed, unlock removes the .lock file.
+fn lock(mbox: &File, mbox_path: &str) -> io::Result<()> {
+  let dotlock = format!("{}.lock", mbox_path);
+  match create_dotlock(&dotlock) {
+    Ok(_) => {},
+    Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && is_stale(&dotlock) => {
+      warn!("Removing stale lock {}", dotlock);
+      let _ = fs::remove_file(&dotlock);
+      try!(create_dotlock(&dotlock));
+    },
+    Err(e) => return Err(e),
+  }
+  let mut fl: libc::flock = unsafe { mem::zeroed() };
+  fl.l_type = libc::F_WRLCK as libc::c_short;
+  fl.l_whence = libc::SEEK_SET as libc::c_short;
+  if unsafe { libc::fcntl(mbox.as_raw_fd(), libc::F_SETLK, &fl) } != 0 {
+    let e = io::Error::last_os_error();
+    let _ = fs::remove_file(&dotlock);
+    return Err(e);
+  }
+  Ok(())
+}
+
+fn create_dotlock(dotlock: &str) -> io::Result<File> {
+  OpenOptions::new().write(true).create_new(true).open(dotlock)
+}
+
+fn unlock(mbox_path: &str) {
+  let _ = fs::remove_file(format!("{}.lock", mbox_path));
+}
+
+fn is_stale(dotlock: &str) -> bool {
+  match fs::metadata(dotlock).and_then(|m| m.modified()) {
+    Ok(modified) => match SystemTime::now().duration_since(modified) {
+      Ok(age) => age.as_secs() > STALE_DOTLOCK_SECS,
+      Err(_) => false,
+    },
+    Err(_) => false,
+  }
+}
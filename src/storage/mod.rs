@@ -0,0 +1,227 @@
+use std::io;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use time;
+use libc;
+
+use config::{Compression, StorageType};
+
+mod compress;
+mod dedup;
+mod encrypt;
+mod eml;
+mod maildir;
+mod mbox;
+
+pub use self::compress::decompress;
+pub use self::encrypt::{check_public_key, decrypt};
+
+//
+// Where archived messages end up. The state machine begins a Delivery per
+// destination once it knows where the message goes, appends the message
+// data as it arrives, and commits when the client has sent all of it;
+// only then is the client told the message is accepted.
+pub trait Storage {
+  fn begin (&self, envelope: &Envelope) -> Result<Box<Delivery>, SpoolError>;
+
+  // Undo a commit, when another destination of the message failed
+  fn remove (&self, location: &str);
+}
+
+pub trait Delivery {
+  fn append (&mut self, data: &[u8]) -> Result<(), SpoolError>;
+
+  // Make the message durable; gives where it was stored
+  fn commit (self: Box<Self>) -> Result<String, SpoolError>;
+
+  fn abort (self: Box<Self>);
+}
+
+// What a backend gets to know about a message
+#[derive(Clone)]
+pub struct Envelope {
+  pub directory: String,   // archive_path, expanded
+  pub file_name: String,   // file_name, expanded
+  pub sender: String,      // normalized reverse-path, empty for the null sender
+  pub recipients: Vec<String>,   // normalized
+  pub message_id: String,  // empty when the message has none
+  pub received: time::Tm,
+}
+
+// The backend, behind encryption and compression when configured; messages
+// are compressed before they are encrypted
+pub fn open (storage: &StorageType, compression: &Compression, encryption_key: &Option<Vec<u8>>) -> Box<Storage> {
+  let mut backend: Box<Storage> = match *storage {
+    StorageType::Eml => Box::new(eml::Eml),
+    StorageType::Maildir => Box::new(maildir::Maildir),
+    StorageType::Mbox => Box::new(mbox::Mbox),
+    StorageType::Dedup(ref blob_path) => Box::new(dedup::Dedup { blob_path: blob_path.clone() }),
+  };
+  if let Some(ref key) = *encryption_key {
+    backend = Box::new(encrypt::Encrypted { storage: backend, public_key: key.clone() });
+  }
+  match *compression {
+    Compression::None => backend,
+    _ => Box::new(compress::Compressed { storage: backend, compression: compression.clone() }),
+  }
+}
+
+// Why a message could not be stored; the client is told to try again
+pub struct SpoolError {
+  pub message: String,
+  full: bool,       // out of disk space or quota
+}
+
+impl SpoolError {
+  pub fn new (message: String, e: &io::Error) -> SpoolError {
+    let full = match e.raw_os_error() {
+      Some(libc::ENOSPC) | Some(libc::EDQUOT) => true,
+      _ => false,
+    };
+    SpoolError { message: format!("{}: {}", message, e), full: full }
+  }
+
+  pub fn other (message: &str) -> SpoolError {
+    SpoolError { message: message.to_string(), full: false }
+  }
+
+  pub fn reply (&self) -> String {
+    if self.full {
+      "452 4.3.1 Insufficient system storage".to_string()
+    } else {
+      "451 4.3.0 Cannot archive message, try again later".to_string()
+    }
+  }
+}
+
+impl Clone for SpoolError {
+  fn clone (&self) -> SpoolError {
+    SpoolError { message: self.message.clone(), full: self.full }
+  }
+}
+
+// Give the synced file at tmp its final name, never replacing a file that
+// is there already; should final_path be taken, as when two messages have
+// the same Message-ID, "-1", "-2".. is added before the extension. Gives
+// the name used; tmp is removed either way.
+pub fn install (tmp: &str, final_path: &str) -> Result<String, SpoolError> {
+  let (stem, extension) = split_extension(final_path);
+  let mut path = final_path.to_string();
+  let mut n = 0;
+  let result = loop {
+    // a hard link, unlike rename, fails when the name is taken
+    match fs::hard_link(tmp, &path) {
+      Ok(_) => break Ok(path),
+      Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && n < INSTALL_ATTEMPTS => {
+        n = n + 1;
+        path = format!("{}-{}{}", stem, n, extension);
+      },
+      Err(e) => break Err(SpoolError::new(format!("Cannot link {} to {}", tmp, path), &e)),
+    }
+  };
+  let _ = fs::remove_file(tmp);
+  let path = try!(result);
+  if n > 0 {
+    warn!("{} exists, duplicate Message-ID? Stored as {}", final_path, path);
+  }
+  if let Err(e) = sync_dir(&path) {
+    let _ = fs::remove_file(&path);
+    return Err(e);
+  }
+  Ok(path)
+}
+
+// A name for a spool file next to base, "base.PID-N.tmp"; the counter
+// tells apart the files of messages spooled to the same name
+pub fn tmp_path (base: &str) -> String {
+  format!("{}.{}-{}.tmp", base, unsafe { libc::getpid() }, TMP_COUNTER.fetch_add(1, Ordering::SeqCst))
+}
+
+static TMP_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Remove a stored message, for Storage::remove
+pub fn remove (location: &str) {
+  if let Err(e) = fs::remove_file(location) {
+    error!("Cannot remove {}: {}", location, e);
+  }
+}
+
+// A file being spooled, removed when it is dropped unless it was given its
+// final name; a delivery dropped half way, when the connection is lost or
+// fails, leaves nothing behind
+pub struct TmpFile {
+  pub file: File,
+  pub path: String,
+  done: bool,
+}
+
+impl TmpFile {
+  pub fn new (file: File, path: String) -> TmpFile {
+    TmpFile { file: file, path: path, done: false }
+  }
+
+  // The file has been moved or removed, and is no longer ours to remove
+  pub fn done (&mut self) {
+    self.done = true;
+  }
+}
+
+impl Drop for TmpFile {
+  fn drop (&mut self) {
+    if self.done {
+      return;
+    }
+    match fs::remove_file(&self.path) {
+      Ok(_) => {},
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+      Err(e) => error!("Cannot remove partial file {}: {}", self.path, e),
+    }
+  }
+}
+
+// how many names install tries
+const INSTALL_ATTEMPTS: u32 = 1000;
+
+// "dir/name.eml.gz.enc" is ("dir/name", ".eml.gz.enc"); the extension is
+// the last one, with the compression and encryption ones after it
+fn split_extension (path: &str) -> (&str, &str) {
+  let name_start = path.rfind('/').map(|i| i + 1).unwrap_or(0);
+  let mut end = path.len();
+  while [".gz", ".zst", ".enc"].iter().any(|s| path[name_start..end].ends_with(s)) {
+    end = name_start + path[name_start..end].rfind('.').unwrap();
+  }
+  match path[name_start..end].rfind('.') {
+    Some(i) if i > 0 => path.split_at(name_start + i),
+    _ => path.split_at(end),
+  }
+}
+
+// A file in directory named start, anything, then rest, and perhaps an
+// added extension; spool files do not count
+pub fn find_similar (directory: &str, start: &str, rest: &str) -> Option<String> {
+  let entries = match fs::read_dir(directory) {
+    Ok(entries) => entries,
+    Err(_) => return None,
+  };
+  for entry in entries {
+    let name = match entry.map(|e| e.file_name().into_string()) {
+      Ok(Ok(name)) => name,
+      _ => continue,
+    };
+    if name.len() > start.len() && name.starts_with(start) && name[start.len()..].contains(rest) && !name.ends_with(".tmp") {
+      return Some(format!("{}/{}", directory, name));
+    }
+  }
+  None
+}
+
+// fsync the directory holding path, so a new name in it is durable
+pub fn sync_dir (path: &str) -> Result<(), SpoolError> {
+  let dir = match Path::new(path).parent() {
+    Some(d) => d,
+    None => return Ok(()),
+  };
+  File::open(dir).and_then(|d| d.sync_all()).map_err(|e| SpoolError::new(format!("Cannot sync {}", dir.display()), &e))
+}
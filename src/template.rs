@@ -0,0 +1,152 @@
+use time;
+
+use pattern;
+
+//
+// Templates for archive_path and file_name. Besides strftime codes and the
+// %{name} recipient captures they may use {name} variables:
+//   known when the recipient is accepted
+//     {recipient} {recipient_local} {recipient_domain} {sender} {sender_domain}
+//     {client_ip} {helo}
+//   known once the message is being spooled
+//     {prefix} {message_id} {message_id_hash} {uuid}
+// Values are sanitized, so they cannot add directories or strftime codes.
+
+// The file name when an archiver does not give one
+pub const DEFAULT_FILE_NAME: &'static str = "{prefix}-{message_id}.eml";
+
+// .. and with mbox storage, a mailbox per day
+pub const DEFAULT_MBOX_NAME: &'static str = "%Y-%m-%d.mbox";
+
+// .. and with dedup storage, the metadata file pointing at the message
+pub const DEFAULT_META_NAME: &'static str = "{prefix}-{message_id}.meta";
+
+pub const ENVELOPE_VARIABLES: [&'static str; 7] = ["recipient", "recipient_local", "recipient_domain", "sender", "sender_domain", "client_ip", "helo"];
+
+pub const MESSAGE_VARIABLES: [&'static str; 4] = ["prefix", "message_id", "message_id_hash", "uuid"];
+
+// Names of the {name} variables used in a template
+pub fn variables(template: &str) -> Vec<String> {
+  let mut variables = Vec::new();
+  let mut i = 0;
+  while let Some((start, end)) = next_variable(template, i) {
+    variables.push(template[start + 1..end].to_string());
+    i = end + 1;
+  }
+  variables
+}
+
+// Check a template only uses known variables, and valid strftime codes
+pub fn check(template: &str) -> Result<(), String> {
+  for v in variables(template) {
+    if !ENVELOPE_VARIABLES.contains(&v.as_str()) && !MESSAGE_VARIABLES.contains(&v.as_str()) {
+      return Err(format!("unknown variable {{{}}} in {}", v, template));
+    }
+  }
+  check_strftime(template)
+}
+
+// Check a template, with its variables and %{name} captures filled in, is
+// a valid strftime format
+fn check_strftime(template: &str) -> Result<(), String> {
+  let captures: Vec<(String, String)> = pattern::path_variables(template).into_iter().map(|v| (v, "x".to_string())).collect();
+  let values: Vec<(String, String)> = variables(template).into_iter().map(|v| (v, "x".to_string())).collect();
+  match time::strftime(&expand(&pattern::expand(template, &captures), &values), &time::now_utc()) {
+    Ok(_) => Ok(()),
+    Err(e) => Err(format!("{} in {}", e, template)),
+  }
+}
+
+// Replace the {name} variables given values for, others are left for later
+pub fn expand(template: &str, values: &[(String, String)]) -> String {
+  let mut expanded = String::new();
+  let mut i = 0;
+  while let Some((start, end)) = next_variable(template, i) {
+    expanded.push_str(&template[i..start]);
+    let name = &template[start + 1..end];
+    match values.iter().find(|&&(ref n, _)| n == name) {
+      Some(&(_, ref value)) => expanded.push_str(&sanitize(value)),
+      None => expanded.push_str(&template[start..end + 1]),
+    }
+    i = end + 1;
+  }
+  expanded.push_str(&template[i..]);
+  expanded
+}
+
+// Values become a single, harmless, path component
+pub fn sanitize(value: &str) -> String {
+  let safe: String = value.chars().map(|c| match c {
+      x @ 'A'...'Z' => x,
+      x @ 'a'...'z' => x,
+      x @ '0'...'9' => x,
+      x @ '.'| x @ '-'| x @ '+'| x @ '@'| x @ '=' | x @ '_' => x,
+      _ => '_' }).collect();
+  if safe.len() == 0 || safe.chars().all(|c| c == '.') {
+    "_".to_string()
+  } else {
+    safe
+  }
+}
+
+// Position of the next "{" and "}" of a variable at or after from; a "{"
+// right after "%" is a recipient capture, not a variable
+fn next_variable(template: &str, from: usize) -> Option<(usize, usize)> {
+  let bytes = template.as_bytes();
+  let mut i = from;
+  while i < bytes.len() {
+    if bytes[i] == b'{' && (i == 0 || bytes[i - 1] != b'%') {
+      match template[i..].find('}') {
+        Some(j) => return Some((i, i + j)),
+        None => return None,
+      }
+    }
+    i = i + 1;
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{expand, sanitize};
+
+  fn values(value: &str) -> Vec<(String, String)> {
+    vec![("helo".to_string(), value.to_string()), ("sender".to_string(), "john@example.com".to_string())]
+  }
+
+  // What the client sends must stay within one path component, and hold
+  // neither strftime codes nor variables
+  #[test]
+  fn client_values_stay_one_component() {
+    for value in ["..", ".", "...", "a/b", "../../etc/passwd", "/", "%Y", "%{1}", "{prefix}", ""].iter() {
+      let expanded = expand("/archive/{helo}/{sender}.eml", &values(value));
+      let components: Vec<&str> = expanded.split('/').collect();
+      assert_eq!(components.len(), 4, "{:?} gave {}", value, expanded);
+      assert!(components[2] != "" && components[2] != "." && components[2] != "..", "{:?} gave {}", value, expanded);
+      assert!(!expanded.contains('%') && !expanded.contains('{'), "{:?} gave {}", value, expanded);
+    }
+  }
+
+  #[test]
+  fn sanitize_keeps_ordinary_values() {
+    assert_eq!(sanitize("mail.example.com"), "mail.example.com");
+    assert_eq!(sanitize("john+list@example.com"), "john+list@example.com");
+    assert_eq!(sanitize("a/b"), "a_b");
+    assert_eq!(sanitize(".."), "_");
+    assert_eq!(sanitize(""), "_");
+  }
+
+  #[test]
+  fn unknown_variables_are_left() {
+    assert_eq!(expand("{prefix}-{message_id}.eml", &values("x")), "{prefix}-{message_id}.eml");
+    assert_eq!(expand("{helo}-{nope}", &values("x")), "x-{nope}");
+    // recipient captures are not variables
+    assert_eq!(expand("%{dept}/{helo}/%Y", &values("x")), "%{dept}/x/%Y");
+  }
+
+  #[test]
+  fn values_are_not_expanded_again() {
+    let once = expand("{helo}", &values("{sender}"));
+    assert_eq!(expand(&once, &values("x")), once);
+  }
+}
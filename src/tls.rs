@@ -0,0 +1,190 @@
+use std::io;
+use std::io::{Read, Write};
+use std::fs::File;
+use futures::Async;
+use tokio_core::io::Io;
+use native_tls::{Pkcs12, TlsAcceptor};
+use openssl;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use tokio_tls::TlsStream;
+
+use config::TlsIdentity;
+
+//
+// The stream below the line codec; connections start out in plain text
+// and are swapped to Tls when the client issues STARTTLS.
+pub enum Stream<T> {
+  Plain(T),
+  Tls(TlsStream<T>),
+}
+
+impl<T: Io> Read for Stream<T> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match *self {
+      Stream::Plain(ref mut s) => s.read(buf),
+      Stream::Tls(ref mut s) => s.read(buf),
+    }
+  }
+}
+
+impl<T: Io> Write for Stream<T> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match *self {
+      Stream::Plain(ref mut s) => s.write(buf),
+      Stream::Tls(ref mut s) => s.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match *self {
+      Stream::Plain(ref mut s) => s.flush(),
+      Stream::Tls(ref mut s) => s.flush(),
+    }
+  }
+}
+
+impl<T: Io> Io for Stream<T> {
+  fn poll_read(&mut self) -> Async<()> {
+    match *self {
+      Stream::Plain(ref mut s) => s.poll_read(),
+      Stream::Tls(ref mut s) => s.poll_read(),
+    }
+  }
+
+  fn poll_write(&mut self) -> Async<()> {
+    match *self {
+      Stream::Plain(ref mut s) => s.poll_write(),
+      Stream::Tls(ref mut s) => s.poll_write(),
+    }
+  }
+}
+
+// Read the TLS identity: a PKCS#12 bundle holding certificate, chain and
+// private key, e.g. made with "openssl pkcs12 -export -in cert.pem -inkey
+// key.pem", or PEM files with the certificate, followed by its chain, and
+// the key
+pub fn load_acceptor(identity: &TlsIdentity, password: &String) -> Result<TlsAcceptor, String> {
+  let (der, password) = match *identity {
+    TlsIdentity::Pkcs12(ref path) => (try!(read_file(path)), password.clone()),
+    TlsIdentity::Pem(ref certificate, ref key) => {
+      let der = try!(pem_to_pkcs12(&try!(read_file(certificate)), &try!(read_file(key)))
+                     .map_err(|err| format!("Cannot parse TLS identity {} due to: {}", identity, err)));
+      (der, "".to_string())
+    },
+  };
+  let pkcs12 = match Pkcs12::from_der(&der, &password) {
+    Ok(p) => p,
+    Err(err) => {
+      return Err(format!("Cannot parse TLS identity {} due to: {}", identity, err));
+    },
+  };
+  match TlsAcceptor::builder(pkcs12).and_then(|builder| builder.build()) {
+    Ok(acceptor) => Ok(acceptor),
+    Err(err) => Err(format!("Cannot set up TLS with identity {} due to: {}", identity, err)),
+  }
+}
+
+fn read_file(path: &String) -> Result<Vec<u8>, String> {
+  let mut file = match File::open(path) {
+    Ok(f) => f,
+    Err(err) => {
+      return Err(format!("Cannot open TLS identity {} due to: {}", path, err));
+    },
+  };
+  let mut contents = Vec::new();
+  if let Err(err) = file.read_to_end(&mut contents) {
+    return Err(format!("Cannot read TLS identity {} due to: {}", path, err));
+  }
+  Ok(contents)
+}
+
+// native-tls takes its identity as PKCS#12, so PEM files are bundled up
+// first, without a password
+fn pem_to_pkcs12(certificate: &[u8], key: &[u8]) -> Result<Vec<u8>, String> {
+  let mut certificates = try!(X509::stack_from_pem(certificate).map_err(|e| e.to_string())).into_iter();
+  let leaf = match certificates.next() {
+    Some(c) => c,
+    None => return Err("no certificate found".to_string()),
+  };
+  let bundle = Stack::new().and_then(|mut chain| {
+    for c in certificates {
+      try!(chain.push(c));
+    }
+    let pkey = try!(PKey::private_key_from_pem(key));
+    let mut builder = openssl::pkcs12::Pkcs12::builder();
+    builder.ca(chain);
+    let pkcs12 = try!(builder.build("", "mail-archiver", &pkey, &leaf));
+    pkcs12.to_der()
+  });
+  bundle.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use std::fs::File;
+  use std::io::Write;
+  use libc;
+  use openssl::asn1::Asn1Time;
+  use openssl::bn::BigNum;
+  use openssl::hash::MessageDigest;
+  use openssl::pkcs12::Pkcs12;
+  use openssl::pkey::{PKey, Private};
+  use openssl::rsa::Rsa;
+  use openssl::x509::{X509, X509Builder, X509NameBuilder};
+
+  use config::TlsIdentity;
+  use super::load_acceptor;
+
+  // A key and a self-signed certificate for localhost
+  fn identity() -> (PKey<Private>, X509) {
+    let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&pkey).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+    (pkey, builder.build())
+  }
+
+  fn write_file(name: &str, contents: &[u8]) -> String {
+    let path = format!("{}/mail-archiver-test-{}-{}", env::temp_dir().display(), unsafe { libc::getpid() }, name);
+    File::create(&path).and_then(|mut f| f.write_all(contents)).unwrap();
+    path
+  }
+
+  #[test]
+  fn pkcs12_identity_loads() {
+    let (pkey, certificate) = identity();
+    let der = Pkcs12::builder().build("secret", "mail-archiver", &pkey, &certificate).unwrap().to_der().unwrap();
+    let path = write_file("identity.p12", &der);
+    let loaded = load_acceptor(&TlsIdentity::Pkcs12(path.clone()), &"secret".to_string());
+    let wrong_password = load_acceptor(&TlsIdentity::Pkcs12(path.clone()), &"wrong".to_string());
+    let _ = fs::remove_file(&path);
+    assert!(loaded.is_ok());
+    assert!(wrong_password.is_err());
+  }
+
+  #[test]
+  fn pem_identity_loads() {
+    let (pkey, certificate) = identity();
+    let certificate_path = write_file("certificate.pem", &certificate.to_pem().unwrap());
+    let key_path = write_file("key.pem", &pkey.private_key_to_pem_pkcs8().unwrap());
+    let loaded = load_acceptor(&TlsIdentity::Pem(certificate_path.clone(), key_path.clone()), &"".to_string());
+    let swapped = load_acceptor(&TlsIdentity::Pem(key_path.clone(), certificate_path.clone()), &"".to_string());
+    let _ = fs::remove_file(&certificate_path);
+    let _ = fs::remove_file(&key_path);
+    assert!(loaded.is_ok());
+    assert!(swapped.is_err());
+  }
+}